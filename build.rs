@@ -0,0 +1,19 @@
+/// Exposes the locked libflatterer version as `LIBFLATTERER_VERSION`, recorded in output
+/// manifests.
+fn main() {
+    println!("cargo:rerun-if-changed=Cargo.lock");
+
+    let version = std::fs::read_to_string("Cargo.lock")
+        .ok()
+        .and_then(|lock| {
+            let mut lines = lock.lines();
+            while let Some(line) = lines.next() {
+                if line == "name = \"libflatterer\"" {
+                    return lines.next()?.strip_prefix("version = \"")?.strip_suffix('"').map(String::from);
+                }
+            }
+            None
+        })
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=LIBFLATTERER_VERSION={}", version);
+}
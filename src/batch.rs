@@ -0,0 +1,342 @@
+use std::path::{Path, PathBuf};
+
+use clap::{Args, ValueEnum};
+use eyre::{bail, WrapErr};
+
+use crate::config::Config;
+use crate::{download, integrity, presets, guess_input_shape, logging, output_file, read_start, run_flatterer_in_thread, url_file_name, Query};
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Zip,
+    Xlsx,
+    Sqlite,
+    Csv,
+    Fields,
+    Tables,
+    Datapackage,
+    Diagram,
+    Sql,
+    Duckdb,
+    Json,
+    Ndjson,
+}
+
+impl Format {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Format::Zip => "zip",
+            Format::Xlsx => "xlsx",
+            Format::Sqlite => "sqlite",
+            Format::Csv => "csv",
+            Format::Fields => "fields",
+            Format::Tables => "tables",
+            Format::Datapackage => "datapackage",
+            Format::Diagram => "diagram",
+            Format::Sql => "sql",
+            Format::Duckdb => "duckdb",
+            Format::Json => "json",
+            Format::Ndjson => "ndjson",
+        }
+    }
+}
+
+/// Options for `flatterer_web convert`, mirroring the query parameters of `/api/convert`.
+#[derive(Args, Debug, Clone)]
+pub struct ConvertArgs {
+    /// JSON file to convert, or a URL starting with `http` to download it from.
+    pub input: String,
+
+    /// Output format.
+    #[arg(long, value_enum, default_value = "zip")]
+    pub format: Format,
+
+    /// File to write the output to.
+    #[arg(long, short)]
+    pub out: PathBuf,
+
+    /// Overwrite `--out` if it already exists.
+    #[arg(long)]
+    pub force: bool,
+
+    #[arg(long)]
+    pub array_key: Option<String>,
+
+    /// Treat input as JSON lines, otherwise this is guessed from the start of the input.
+    #[arg(long)]
+    pub json_lines: bool,
+
+    #[arg(long)]
+    pub main_table_name: Option<String>,
+
+    #[arg(long)]
+    pub inline_one_to_one: bool,
+
+    #[arg(long)]
+    pub json_schema: Option<String>,
+
+    /// JSON Schema file used for field order and titles.
+    #[arg(long)]
+    pub schema: Option<PathBuf>,
+
+    #[arg(long)]
+    pub table_prefix: Option<String>,
+
+    #[arg(long)]
+    pub path_separator: Option<String>,
+
+    #[arg(long)]
+    pub schema_titles: Option<String>,
+
+    /// fields.csv to control field order, titles and inclusion.
+    #[arg(long)]
+    pub fields: Option<PathBuf>,
+
+    /// tables.csv to control table titles and inclusion.
+    #[arg(long)]
+    pub tables: Option<PathBuf>,
+
+    #[arg(long)]
+    pub fields_only: bool,
+
+    #[arg(long)]
+    pub tables_only: bool,
+
+    #[arg(long)]
+    pub pushdown: Option<String>,
+
+    /// Saved preset whose options and fields/tables files are used, unless given here.
+    #[arg(long)]
+    pub preset: Option<String>,
+
+    /// Add `output.duckdb` to the zip.
+    #[arg(long)]
+    pub duckdb: bool,
+
+    /// Do not keep the header row of each xlsx sheet in view.
+    #[arg(long)]
+    pub no_xlsx_freeze_header: bool,
+
+    /// Do not size xlsx columns to their contents.
+    #[arg(long)]
+    pub no_xlsx_autofit: bool,
+
+    /// Add a `README` sheet describing each table and field to the xlsx output.
+    #[arg(long)]
+    pub xlsx_readme: bool,
+
+    /// Continue xlsx sheets with too many rows on further sheets rather than cutting them short.
+    #[arg(long)]
+    pub xlsx_split: bool,
+
+    /// With `--format json` or `ndjson`, write only this table rather than a zip of all of them.
+    #[arg(long)]
+    pub table: Option<String>,
+
+    /// With `--format diagram`, write only the `mermaid`, `dot` or `svg` diagram.
+    #[arg(long)]
+    pub diagram_format: Option<String>,
+
+    /// Validate each record against the JSON Schema, adding a `validation_errors` table.
+    #[arg(long)]
+    pub validate: bool,
+}
+
+impl ConvertArgs {
+    fn query(&self) -> Query {
+        Query {
+            output_format: Some(self.format.as_str().to_string()),
+            array_key: self.array_key.clone(),
+            json_lines: self.json_lines.then_some(true),
+            main_table_name: self.main_table_name.clone(),
+            inline_one_to_one: self.inline_one_to_one.then_some(true),
+            json_schema: self.json_schema.clone(),
+            table_prefix: self.table_prefix.clone(),
+            path_separator: self.path_separator.clone(),
+            schema_titles: self.schema_titles.clone(),
+            fields_only: self.fields_only.then_some(true),
+            tables_only: self.tables_only.then_some(true),
+            pushdown: self.pushdown.clone(),
+            validate: self.validate.then_some(true),
+            preset: self.preset.clone(),
+            diagram_format: self.diagram_format.clone(),
+            table: self.table.clone(),
+            duckdb: self.duckdb.then_some(true),
+            xlsx_freeze_header: self.no_xlsx_freeze_header.then_some(false),
+            xlsx_autofit: self.no_xlsx_autofit.then_some(false),
+            xlsx_readme: self.xlsx_readme.then_some(true),
+            xlsx_split: self.xlsx_split.then_some(true),
+            ..Default::default()
+        }
+    }
+}
+
+/// Prefix of the working directories of conversions, which the server's `clean_tmp` leaves
+/// alone however long they run.
+pub(crate) const CLI_JOB_PREFIX: &str = "flatterer-cli-";
+
+/// Run a single conversion without the server, producing the same output as `/api/convert`.
+pub fn convert(config: &Config, args: ConvertArgs) -> eyre::Result<()> {
+    logging::init();
+    actix_web::rt::System::new().block_on(convert_async(config, args))
+}
+
+fn copy_into(from: &Path, to: &Path) -> eyre::Result<()> {
+    std::fs::copy(from, to).wrap_err_with(|| format!("could not read `{}`", from.display()))?;
+    Ok(())
+}
+
+async fn convert_async(config: &Config, args: ConvertArgs) -> eyre::Result<()> {
+    if args.out.exists() && !args.force {
+        bail!("`{}` already exists, use --force to overwrite it", args.out.display());
+    }
+
+    let job_dir = tempfile::Builder::new().prefix(CLI_JOB_PREFIX).tempdir()?;
+    let job_path = job_dir.path().to_path_buf();
+
+    let input_file_name = if args.input.starts_with("http") {
+        download(args.input.clone(), job_path.clone(), config.max_size_bytes()).await?;
+        url_file_name(&args.input)
+    } else {
+        let input = Path::new(&args.input);
+        copy_into(input, &job_path.join("download.json"))?;
+        input.file_name().map(|name| name.to_string_lossy().to_string())
+    };
+    integrity::record_input(&job_path, input_file_name.as_deref(), None).wrap_err("could not record the input")?;
+    if let Some(fields) = &args.fields {
+        copy_into(fields, &job_path.join("fields.csv"))?;
+    }
+    if let Some(tables) = &args.tables {
+        copy_into(tables, &job_path.join("tables.csv"))?;
+    }
+    if let Some(schema) = &args.schema {
+        copy_into(schema, &job_path.join("schema.json"))?;
+    }
+
+    let mut query = args.query();
+    if let Some(preset) = &args.preset {
+        let keep: Vec<&str> = [(&args.fields, "fields.csv"), (&args.tables, "tables.csv")]
+            .into_iter()
+            .filter(|(given, _)| given.is_some())
+            .map(|(_, file_name)| file_name)
+            .collect();
+        presets::apply(&config.presets_dir, preset, &mut query, &keep).map_err(|err| eyre::eyre!(err))?;
+    }
+    let start = read_start(&job_path.join("download.json"))?;
+    let (path, json_lines, guess_text) = guess_input_shape(&query, &start)
+        .map_err(|err| eyre::eyre!("could not work out the shape of the input JSON: {}", err))?;
+    if !guess_text.is_empty() {
+        log::info!(guess = guess_text; "input shape guessed");
+    }
+
+    let output_path = job_path.join("output");
+    // Off the runtime, as fetching a `json_schema` URL blocks.
+    run_flatterer_in_thread(query.clone(), job_path.clone(), output_path.clone(), json_lines, path)
+        .await
+        .wrap_err("flattening failed")?;
+
+    let file = output_file(&query, &output_path, &job_path)?;
+    std::fs::copy(&file, &args.out)
+        .wrap_err_with(|| format!("could not write `{}`", args.out.display()))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::Read;
+
+    use clap::Parser;
+
+    use super::*;
+
+    #[derive(Parser, Debug)]
+    struct Cli {
+        #[command(flatten)]
+        convert: ConvertArgs,
+    }
+
+    fn args(args: &[&str]) -> ConvertArgs {
+        Cli::try_parse_from(std::iter::once("convert").chain(args.iter().copied())).unwrap().convert
+    }
+
+    #[actix_web::test]
+    async fn convert_to_file() {
+        let out_dir = tempfile::tempdir().unwrap();
+        let csv_out = out_dir.path().join("games.csv");
+        let zip_out = out_dir.path().join("games.zip");
+        let config = Config::default();
+
+        convert_async(&config, args(&["fixtures/basic.json", "--format", "csv", "--out", csv_out.to_str().unwrap()])).await.unwrap();
+        let csv = std::fs::read_to_string(&csv_out).unwrap();
+        insta::assert_snapshot!(csv);
+
+        convert_async(&config, args(&["fixtures/basic.json", "--out", zip_out.to_str().unwrap()])).await.unwrap();
+        let mut zip = zip::ZipArchive::new(File::open(&zip_out).unwrap()).unwrap();
+        let mut main_csv = String::new();
+        zip.by_name("csv/main.csv").unwrap().read_to_string(&mut main_csv).unwrap();
+        assert_eq!(main_csv, csv);
+        let mut manifest = String::new();
+        zip.by_name("manifest.json").unwrap().read_to_string(&mut manifest).unwrap();
+        let manifest: serde_json::Value = serde_json::from_str(&manifest).unwrap();
+        assert_eq!(manifest["input"]["file_name"], "basic.json");
+
+        let error = convert_async(&config, args(&["fixtures/basic.json", "--format", "csv", "--out", csv_out.to_str().unwrap()]))
+            .await
+            .unwrap_err();
+        assert!(error.to_string().ends_with("already exists, use --force to overwrite it"), "{}", error);
+        convert_async(&config, args(&["fixtures/basic.json", "--format", "csv", "--force", "--out", csv_out.to_str().unwrap()]))
+            .await
+            .unwrap();
+    }
+
+    /// Serves `body` as JSON to every request on a local port, returning its URL.
+    fn serve_json(body: &'static str) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/schema.json", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut request = [0; 4096];
+                let _ = stream.read(&mut request);
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                let _ = std::io::Write::write_all(&mut stream, response.as_bytes());
+            }
+        });
+        url
+    }
+
+    #[actix_web::test]
+    async fn convert_with_schema_url() {
+        let schema_url = serve_json(
+            r#"{"type": "object", "properties": {"id": {"type": "string", "title": "Game Id"}, "title": {"type": "string", "title": "Game Title"}}}"#,
+        );
+        let out_dir = tempfile::tempdir().unwrap();
+        let zip_out = out_dir.path().join("games.zip");
+        let xlsx_out = out_dir.path().join("games.xlsx");
+        let config = Config::default();
+
+        let schema_args = ["fixtures/basic.json", "--json-schema", &schema_url, "--schema-titles", "full"];
+        let zip_args = ["--validate", "--out", zip_out.to_str().unwrap()];
+        convert_async(&config, args(&[&schema_args[..], &zip_args].concat())).await.unwrap();
+        let xlsx_args = ["--format", "xlsx", "--xlsx-readme", "--out", xlsx_out.to_str().unwrap()];
+        convert_async(&config, args(&[&schema_args[..], &xlsx_args].concat())).await.unwrap();
+
+        let mut zip = zip::ZipArchive::new(File::open(&zip_out).unwrap()).unwrap();
+        let mut main_csv = String::new();
+        zip.by_name("csv/main.csv").unwrap().read_to_string(&mut main_csv).unwrap();
+        assert!(main_csv.starts_with("_link,Game Id,Game Title,"), "{}", main_csv);
+        let mut errors_csv = String::new();
+        zip.by_name("csv/validation_errors.csv").unwrap().read_to_string(&mut errors_csv).unwrap();
+        assert_eq!(errors_csv.lines().filter(|line| line.starts_with("0,/id,") || line.starts_with("1,/id,")).count(), 2, "{}", errors_csv);
+
+        let mut workbook = zip::ZipArchive::new(File::open(&xlsx_out).unwrap()).unwrap();
+        let mut sheets = String::new();
+        workbook.by_name("xl/workbook.xml").unwrap().read_to_string(&mut sheets).unwrap();
+        assert!(sheets.contains(r#"name="README""#), "{}", sheets);
+    }
+}
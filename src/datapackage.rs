@@ -0,0 +1,228 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use serde_json::{json, Value};
+
+use crate::relationships;
+
+/// The `datapackage.json` libflatterer writes into an output, rewritten by `write_package`.
+const DATAPACKAGE_FILE: &str = "datapackage.json";
+
+fn read_datapackage(output_path: &Path) -> Option<Value> {
+    File::open(output_path.join(DATAPACKAGE_FILE))
+        .ok()
+        .and_then(|file| serde_json::from_reader(BufReader::new(file)).ok())
+}
+
+/// `fields.csv` only has `date`, the types libflatterer detected tell dates and datetimes apart.
+pub fn detected_datetimes(output_path: &Path) -> HashSet<(String, String)> {
+    let datapackage = read_datapackage(output_path);
+    let mut datetimes = HashSet::new();
+    let Some(resources) = datapackage.as_ref().and_then(|datapackage| datapackage["resources"].as_array()) else {
+        return datetimes;
+    };
+    for resource in resources {
+        let table_name = resource["flatterer_name"].as_str().unwrap_or_default();
+        for field in resource["schema"]["fields"].as_array().into_iter().flatten() {
+            if field["type"] == "datetime" {
+                datetimes.insert((table_name.to_string(), field["name"].as_str().unwrap_or_default().to_string()));
+            }
+        }
+    }
+    datetimes
+}
+
+fn table_schema_type(field_type: &str, datetime: bool) -> &'static str {
+    match field_type {
+        "text" | "string" => "string",
+        "number" => "number",
+        "integer" => "integer",
+        "boolean" => "boolean",
+        "date" if datetime => "datetime",
+        "date" => "date",
+        "datetime" => "datetime",
+        "object" => "object",
+        "array" => "array",
+        _ => "any",
+    }
+}
+
+/// A resource name as the Data Package spec allows: lower case letters, numbers, `-`, `_`
+/// and `.`, unique within the package.
+fn resource_name(title: &str, used: &mut HashSet<String>) -> String {
+    let mut name: String = title
+        .chars()
+        .map(|c| c.to_ascii_lowercase())
+        .map(|c| if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.') { c } else { '_' })
+        .collect();
+    if name.is_empty() {
+        name = "table".to_string();
+    }
+    let base = name.clone();
+    let mut suffix = 2;
+    while used.contains(&name) {
+        name = format!("{}_{}", base, suffix);
+        suffix += 1;
+    }
+    used.insert(name.clone());
+    name
+}
+
+/// Make a flatterer output that has CSV files a Frictionless data package, by rewriting the
+/// `datapackage.json` libflatterer wrote with a valid Table Schema for each table, using the
+/// titles and types of any edits, and resources pointing at the CSV files in `csv/`. Each
+/// table's `_link` is its primary key and its `_link_<table>` fields are foreign keys to the
+/// `_link` of that parent table. Returns the directory of the package, which is the output.
+pub fn write_package(output_path: &Path) -> std::io::Result<PathBuf> {
+    let detected = read_datapackage(output_path);
+    let detected_resources: HashMap<&str, &Value> = detected
+        .as_ref()
+        .and_then(|datapackage| datapackage["resources"].as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|resource| Some((resource["flatterer_name"].as_str()?, resource)))
+        .collect();
+    let datetimes = detected_datetimes(output_path);
+    let tables = relationships::output_tables(output_path)?;
+
+    let mut used = HashSet::new();
+    let names: HashMap<&str, String> = tables
+        .iter()
+        .map(|table| (table.name.as_str(), resource_name(&table.title, &mut used)))
+        .collect();
+    let relationships = relationships::find(&tables);
+
+    let mut resources = vec![];
+    for table in &tables {
+        let name = &names[table.name.as_str()];
+        let fields: Vec<Value> = table
+            .fields
+            .iter()
+            .map(|field| {
+                let datetime = datetimes.contains(&(table.name.clone(), field.name.clone()));
+                json!({
+                    "name": field.title,
+                    "title": field.title,
+                    "type": table_schema_type(&field.field_type, datetime),
+                })
+            })
+            .collect();
+
+        let mut schema = json!({"fields": fields});
+        if let Some(link) = table.link_title() {
+            schema["primaryKey"] = json!([link]);
+        }
+
+        let foreign_keys: Vec<Value> = relationships
+            .iter()
+            .filter(|relationship| relationship.child_table == table.name)
+            .map(|relationship| {
+                json!({
+                    "fields": [relationship.child_key],
+                    "reference": {"resource": names[relationship.parent_table.as_str()], "fields": [relationship.parent_key]},
+                })
+            })
+            .collect();
+        if !foreign_keys.is_empty() {
+            schema["foreignKeys"] = json!(foreign_keys);
+        }
+
+        // Anything else libflatterer recorded about the table is kept.
+        let mut resource = detected_resources.get(table.name.as_str()).cloned().cloned().unwrap_or_else(|| json!({}));
+        for (key, value) in [
+            ("profile", json!("tabular-data-resource")),
+            ("name", json!(name)),
+            ("flatterer_name", json!(table.name)),
+            ("title", json!(table.title)),
+            ("path", json!(format!("csv/{}.csv", table.title))),
+            ("format", json!("csv")),
+            ("mediatype", json!("text/csv")),
+            ("encoding", json!("utf-8")),
+            ("schema", schema),
+        ] {
+            resource[key] = value;
+        }
+        resources.push(resource);
+    }
+
+    let datapackage = json!({"profile": "tabular-data-package", "resources": resources});
+    std::fs::write(output_path.join(DATAPACKAGE_FILE), serde_json::to_string_pretty(&datapackage)?)?;
+    Ok(output_path.to_path_buf())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The values of each column of a resource's CSV, by column name.
+    fn columns(package_path: &Path, resource: &Value) -> HashMap<String, Vec<String>> {
+        let mut reader = csv::Reader::from_path(package_path.join(resource["path"].as_str().unwrap())).unwrap();
+        let headers: Vec<String> = reader.headers().unwrap().iter().map(String::from).collect();
+        let mut columns: HashMap<String, Vec<String>> = headers.iter().map(|header| (header.clone(), vec![])).collect();
+        for record in reader.records() {
+            for (header, value) in headers.iter().zip(record.unwrap().iter()) {
+                columns.get_mut(header).unwrap().push(value.to_string());
+            }
+        }
+        columns
+    }
+
+    fn valid_value(field_type: &str, value: &str) -> bool {
+        value.is_empty()
+            || match field_type {
+                "integer" => value.parse::<i64>().is_ok(),
+                "number" => value.parse::<f64>().is_ok(),
+                "boolean" => matches!(value, "true" | "false"),
+                "date" => value.len() == 10 && value.chars().enumerate().all(|(i, c)| if i == 4 || i == 7 { c == '-' } else { c.is_ascii_digit() }),
+                _ => true,
+            }
+    }
+
+    #[test]
+    fn package_keys_and_types() {
+        let job_dir = crate::tests::flatten_fixture("basic.json");
+        let output_path = job_dir.path().join("output");
+        let package_path = write_package(&output_path).unwrap();
+        assert_eq!(package_path, output_path);
+        assert!(!output_path.join("datapackage").exists());
+
+        let datapackage = read_datapackage(&package_path).unwrap();
+        let resources = datapackage["resources"].as_array().unwrap();
+        let data: HashMap<&str, HashMap<String, Vec<String>>> = resources
+            .iter()
+            .map(|resource| (resource["name"].as_str().unwrap(), columns(&package_path, resource)))
+            .collect();
+
+        for resource in resources {
+            let name = resource["name"].as_str().unwrap();
+            let columns = &data[name];
+            let schema = &resource["schema"];
+            let fields = schema["fields"].as_array().unwrap();
+            assert_eq!(fields.len(), columns.len(), "{}", name);
+            for field in fields {
+                let values = &columns[field["name"].as_str().unwrap()];
+                let field_type = field["type"].as_str().unwrap();
+                assert!(values.iter().all(|value| valid_value(field_type, value)), "{}.{} is not all {}", name, field["name"], field_type);
+            }
+
+            for key in schema["primaryKey"].as_array().unwrap() {
+                let values = &columns[key.as_str().unwrap()];
+                assert_eq!(values.iter().collect::<HashSet<_>>().len(), values.len(), "{} has duplicate keys", name);
+            }
+            for foreign_key in schema["foreignKeys"].as_array().into_iter().flatten() {
+                let child_values = &columns[foreign_key["fields"][0].as_str().unwrap()];
+                let parent = &data[foreign_key["reference"]["resource"].as_str().unwrap()];
+                let parent_values: HashSet<&String> = parent[foreign_key["reference"]["fields"][0].as_str().unwrap()].iter().collect();
+                assert!(child_values.iter().all(|value| parent_values.contains(value)), "{} has dangling keys", name);
+            }
+        }
+
+        let schemas: Vec<Value> = resources
+            .iter()
+            .map(|resource| json!({"name": resource["name"], "path": resource["path"], "flatterer_name": resource["flatterer_name"], "schema": resource["schema"]}))
+            .collect();
+        insta::assert_yaml_snapshot!(schemas);
+    }
+}
@@ -0,0 +1,335 @@
+use std::fs::File;
+use std::path::Path;
+use std::time::Duration;
+
+use serde_json::{json, Value};
+use tokio_postgres_rustls::MakeRustlsConnect;
+
+use crate::config::{self, Config};
+use crate::relationships::{self, Relationship};
+use crate::tls::{self, CertificateCheck};
+use crate::{datapackage, sql, Query};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Create missing tables and add the rows to existing ones.
+    Append,
+    /// Drop the tables if they exist and create them again.
+    Replace,
+}
+
+impl Mode {
+    fn as_str(self) -> &'static str {
+        match self {
+            Mode::Append => "append",
+            Mode::Replace => "replace",
+        }
+    }
+}
+
+/// Where `output_format=postgres` loads the flattened tables: a database from the config
+/// named by `destination`, with the `destination_schema` and `destination_mode` options.
+#[derive(Debug, Clone)]
+pub struct Destination {
+    name: String,
+    connection_string: String,
+    schema: Option<String>,
+    mode: Mode,
+}
+
+impl Destination {
+    pub fn from_query(config: &Config, query: &Query) -> Result<Destination, String> {
+        let name = query
+            .destination
+            .clone()
+            .filter(|name| !name.is_empty())
+            .ok_or_else(|| "`output_format=postgres` needs a `destination` database".to_string())?;
+        let connection_string = config
+            .databases
+            .get(&name)
+            .cloned()
+            .ok_or_else(|| format!("destination `{}` is not a configured database", name))?;
+        let mode = match query.destination_mode.as_deref() {
+            None | Some("append") => Mode::Append,
+            Some("replace") => Mode::Replace,
+            Some(mode) => return Err(format!("unknown destination_mode `{}`, use append or replace", mode)),
+        };
+        let schema = query.destination_schema.clone().filter(|schema| !schema.is_empty());
+        Ok(Destination { name, connection_string, schema, mode })
+    }
+
+    fn qualify(&self, table: &str) -> String {
+        match &self.schema {
+            Some(schema) => format!("{}.{}", sql::postgresql_quote(schema), sql::postgresql_quote(table)),
+            None => sql::postgresql_quote(table),
+        }
+    }
+
+    /// Connects with TLS as `sslmode` asks, by default when the server supports it.
+    fn connect(&self) -> Result<postgres::Client, String> {
+        let (mut pg_config, check) = pg_config(&self.connection_string).map_err(|e| e.to_string())?;
+        if pg_config.get_connect_timeout().is_none() {
+            pg_config.connect_timeout(CONNECT_TIMEOUT);
+        }
+        if pg_config.get_application_name().is_none() {
+            pg_config.application_name("flatterer-web");
+        }
+        let tls_config = tls::client_config(check).map_err(|e| e.to_string())?;
+        pg_config.connect(MakeRustlsConnect::new(tls_config)).map_err(|e| e.to_string())
+    }
+}
+
+/// Parses a connection string along with how its server's certificate is checked. libpq's
+/// `verify-ca` and `verify-full` are read here, as the client itself only knows `require`.
+pub fn pg_config(connection_string: &str) -> Result<(postgres::Config, CertificateCheck), postgres::Error> {
+    let mut check = CertificateCheck::None;
+    let connection_string = config::replace_param(connection_string, "sslmode", |mode| match mode.trim_matches('\'') {
+        "verify-ca" => {
+            check = CertificateCheck::Chain;
+            "require".to_string()
+        }
+        "verify-full" => {
+            check = CertificateCheck::Full;
+            "require".to_string()
+        }
+        _ => mode.to_string(),
+    })
+    .unwrap_or_else(|| connection_string.to_string());
+    Ok((connection_string.parse()?, check))
+}
+
+fn push_error(error: impl std::fmt::Display) -> std::io::Error {
+    std::io::Error::other(error.to_string())
+}
+
+/// Load the CSV tables of an output into the destination in one transaction, so nothing is
+/// changed if any table fails. Tables are created with the column types of the SQL output
+/// and indexes on their `_link` columns, but without key constraints so appending the
+/// output of another conversion does not clash. Returns the rows loaded into each table.
+pub fn push(destination: &Destination, output_path: &Path) -> std::io::Result<Value> {
+    let tables = relationships::output_tables(output_path)?;
+    let relationships: Vec<Relationship> = relationships::find(&tables);
+    let datetimes = datapackage::detected_datetimes(output_path);
+
+    let mut client = destination
+        .connect()
+        .map_err(|e| push_error(format!("could not connect to destination `{}`: {}", destination.name, e)))?;
+    let mut transaction = client.transaction().map_err(push_error)?;
+
+    if let Some(schema) = &destination.schema {
+        transaction
+            .batch_execute(&format!("CREATE SCHEMA IF NOT EXISTS {}", sql::postgresql_quote(schema)))
+            .map_err(push_error)?;
+    }
+
+    let mut results = vec![];
+    for table in &tables {
+        let qualified = destination.qualify(&table.title);
+        let exists: Option<String> = transaction
+            .query_one("SELECT to_regclass($1)::TEXT", &[&qualified])
+            .map_err(push_error)?
+            .get(0);
+
+        if exists.is_some() && destination.mode == Mode::Replace {
+            transaction
+                .batch_execute(&format!("DROP TABLE {} CASCADE", qualified))
+                .map_err(push_error)?;
+        }
+        let create = exists.is_none() || destination.mode == Mode::Replace;
+        if create {
+            let columns = sql::postgresql_columns(table, &datetimes);
+            transaction
+                .batch_execute(&format!("CREATE TABLE {} ({})", qualified, columns.join(", ")))
+                .map_err(push_error)?;
+            for field in table.fields.iter().filter(|field| field.name.starts_with("_link")) {
+                transaction
+                    .batch_execute(&format!("CREATE INDEX ON {} ({})", qualified, sql::postgresql_quote(&field.title)))
+                    .map_err(push_error)?;
+            }
+        }
+
+        let columns: Vec<String> = table.fields.iter().map(|field| sql::postgresql_quote(&field.title)).collect();
+        let copy = format!("COPY {} ({}) FROM STDIN WITH (FORMAT csv, HEADER true)", qualified, columns.join(", "));
+        let mut writer = transaction
+            .copy_in(&copy)
+            .map_err(|e| push_error(format!("could not load `{}`: {}", table.title, e)))?;
+        let mut file = File::open(output_path.join("csv").join(format!("{}.csv", table.title)))?;
+        std::io::copy(&mut file, &mut writer)?;
+        let rows = writer
+            .finish()
+            .map_err(|e| push_error(format!("could not load `{}`: {}", table.title, e)))?;
+
+        log::info!(table = table.title.as_str(), rows = rows, created = create; "table pushed");
+        results.push(json!({"table": table.title, "rows": rows, "created": create}));
+    }
+    transaction.commit().map_err(push_error)?;
+
+    let total_rows: u64 = results.iter().filter_map(|result| result["rows"].as_u64()).sum();
+    log::info!(destination = destination.name.as_str(), tables = results.len() as u64, rows = total_rows; "push finished");
+    Ok(json!({
+        "destination": destination.name,
+        "schema": destination.schema,
+        "mode": destination.mode.as_str(),
+        "tables": results,
+        "relationships": relationships,
+        "total_rows": total_rows,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+    use std::process::Command;
+
+    use super::*;
+    use crate::tests::flatten_fixture;
+
+    /// A PostgreSQL server in a temporary directory, stopped when dropped.
+    struct TestPostgres {
+        bin: PathBuf,
+        dir: tempfile::TempDir,
+        port: u16,
+    }
+
+    impl TestPostgres {
+        /// Starts a server with `initdb` and `pg_ctl` from `PATH` or the Debian install
+        /// directories.
+        fn start() -> TestPostgres {
+            let installed = std::fs::read_dir("/usr/lib/postgresql")
+                .into_iter()
+                .flatten()
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path().join("bin"));
+            let bin = std::iter::once(PathBuf::new())
+                .chain(installed)
+                .find(|bin| Command::new(bin.join("pg_ctl")).arg("--version").output().is_ok_and(|output| output.status.success()));
+            let bin = bin.expect("PostgreSQL's pg_ctl is not in PATH or /usr/lib/postgresql");
+
+            let dir = tempfile::tempdir().unwrap();
+            let data = dir.path().join("data");
+            let initdb = Command::new(bin.join("initdb"))
+                .args(["--auth=trust", "--username=flat", "--encoding=UTF8", "-D"])
+                .arg(&data)
+                .output()
+                .unwrap();
+            assert!(initdb.status.success(), "initdb failed: {}", String::from_utf8_lossy(&initdb.stderr).trim());
+
+            let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+            let options = format!("-p {} -k {} -c listen_addresses=127.0.0.1", port, dir.path().display());
+            let started = Command::new(bin.join("pg_ctl"))
+                .args(["-w", "-o", &options, "-D"])
+                .arg(&data)
+                .arg("-l")
+                .arg(dir.path().join("postgres.log"))
+                .arg("start")
+                .output()
+                .unwrap();
+            assert!(started.status.success(), "{}", std::fs::read_to_string(dir.path().join("postgres.log")).unwrap_or_default());
+            TestPostgres { bin, dir, port }
+        }
+
+        fn connection_string(&self) -> String {
+            format!("host=127.0.0.1 port={} user=flat dbname=postgres", self.port)
+        }
+    }
+
+    impl Drop for TestPostgres {
+        fn drop(&mut self) {
+            let _ = Command::new(self.bin.join("pg_ctl"))
+                .args(["-w", "-m", "immediate", "-D"])
+                .arg(self.dir.path().join("data"))
+                .arg("stop")
+                .output();
+        }
+    }
+
+    fn query(destination: &str, mode: Option<&str>, schema: Option<&str>) -> Query {
+        Query {
+            destination: Some(destination.to_string()),
+            destination_mode: mode.map(str::to_string),
+            destination_schema: schema.map(str::to_string),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn ssl_modes() {
+        let modes: Vec<String> = [
+            "host=localhost",
+            "host=localhost sslmode=disable",
+            "host=localhost sslmode=require",
+            "host=localhost sslmode=verify-ca",
+            "host=localhost sslmode='verify-full'",
+            "postgres://localhost/db?sslmode=verify-full",
+        ]
+        .into_iter()
+        .map(|connection| {
+            let (pg_config, check) = pg_config(connection).unwrap();
+            format!("{} => {:?} {:?}", connection, pg_config.get_ssl_mode(), check)
+        })
+        .collect();
+        insta::assert_yaml_snapshot!(modes);
+        assert!(pg_config("host=localhost sslmode=verify").is_err());
+    }
+
+    #[test]
+    fn destination_errors() {
+        let config = Config {
+            databases: BTreeMap::from([("local".to_string(), "host=localhost".to_string())]),
+            ..Default::default()
+        };
+        let errors: Vec<String> = [query("", None, None), query("remote", None, None), query("local", Some("merge"), None)]
+            .iter()
+            .map(|query| Destination::from_query(&config, query).unwrap_err())
+            .collect();
+        insta::assert_yaml_snapshot!(errors);
+
+        let destination = Destination::from_query(&config, &query("local", None, Some(""))).unwrap();
+        assert_eq!(destination.mode, Mode::Append);
+        assert_eq!(destination.schema, None);
+    }
+
+    #[test]
+    #[ignore = "needs PostgreSQL's initdb and pg_ctl and a non-root user, run with --ignored"]
+    fn push_fixture() {
+        let postgres = TestPostgres::start();
+        let config = Config {
+            databases: BTreeMap::from([("test".to_string(), postgres.connection_string())]),
+            ..Default::default()
+        };
+        let job_dir = flatten_fixture("basic.json");
+        let output_path = job_dir.path().join("output");
+
+        let mut pushed = vec![];
+        for (mode, schema) in [(None, None), (Some("append"), None), (Some("replace"), None), (None, Some("flat"))] {
+            let destination = Destination::from_query(&config, &query("test", mode, schema)).unwrap();
+            pushed.push(push(&destination, &output_path).unwrap());
+        }
+        insta::assert_yaml_snapshot!(pushed);
+
+        let mut client = postgres::Client::connect(&postgres.connection_string(), postgres::NoTls).unwrap();
+        let counts: Vec<(String, i64)> = client
+            .query(
+                "SELECT table_schema || '.' || table_name, (xpath('/row/count/text()', query_to_xml(format('SELECT count(*) FROM %I.%I', table_schema, table_name), false, true, '')))[1]::TEXT::BIGINT \
+                 FROM information_schema.tables WHERE table_schema IN ('public', 'flat') ORDER BY 1",
+                &[],
+            )
+            .unwrap()
+            .iter()
+            .map(|row| (row.get(0), row.get(1)))
+            .collect();
+        insta::assert_yaml_snapshot!(counts);
+
+        let bad = Config {
+            databases: BTreeMap::from([("test".to_string(), format!("{} dbname=missing", postgres.connection_string()))]),
+            ..Default::default()
+        };
+        let destination = Destination::from_query(&bad, &query("test", None, None)).unwrap();
+        let error = push(&destination, &output_path).unwrap_err().to_string();
+        assert!(error.starts_with("could not connect to destination `test`"), "{}", error);
+    }
+}
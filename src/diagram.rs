@@ -0,0 +1,348 @@
+use std::collections::HashSet;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+use crate::relationships::{self, Relationship, Table};
+
+/// Directory within the output the diagrams are written to.
+pub const DIAGRAM_DIR: &str = "diagram";
+
+const FORMATS: [&str; 3] = ["mermaid", "dot", "svg"];
+
+/// File name and MIME type of a `diagram_format`, or `None` if it is not one.
+pub fn format_file(diagram_format: &str) -> Option<(&'static str, &'static str)> {
+    match diagram_format {
+        "mermaid" => Some(("diagram.mmd", "text/vnd.mermaid; charset=utf-8")),
+        "dot" => Some(("diagram.dot", "text/vnd.graphviz; charset=utf-8")),
+        "svg" => Some(("diagram.svg", "image/svg+xml")),
+        _ => None,
+    }
+}
+
+pub fn check_format(diagram_format: Option<&str>) -> Result<(), String> {
+    match diagram_format {
+        Some(diagram_format) if format_file(diagram_format).is_none() => Err(format!(
+            "unknown diagram_format `{}`, use one of {}",
+            diagram_format,
+            FORMATS.join(", ")
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn key_marker(field_name: &str) -> Option<&'static str> {
+    if field_name == "_link" {
+        Some("PK")
+    } else if field_name.starts_with("_link_") {
+        Some("FK")
+    } else {
+        None
+    }
+}
+
+/// An identifier Mermaid accepts, which titles with spaces or punctuation are not.
+fn mermaid_id(name: &str) -> String {
+    let id: String = name.chars().map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '-' { c } else { '_' }).collect();
+    match id.chars().next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => id,
+        _ => format!("_{}", id),
+    }
+}
+
+pub fn mermaid(tables: &[Table], relationships: &[Relationship]) -> String {
+    let mut used = HashSet::new();
+    let ids: Vec<String> = tables
+        .iter()
+        .map(|table| {
+            let base = mermaid_id(&table.title);
+            let mut id = base.clone();
+            let mut suffix = 2;
+            while !used.insert(id.clone()) {
+                id = format!("{}_{}", base, suffix);
+                suffix += 1;
+            }
+            id
+        })
+        .collect();
+    let id_of = |table_name: &str| tables.iter().position(|table| table.name == table_name).map(|index| ids[index].as_str());
+
+    let mut out = String::from("erDiagram\n");
+    for (table, id) in tables.iter().zip(&ids) {
+        if *id == table.title {
+            writeln!(out, "    {} {{", id).unwrap();
+        } else {
+            writeln!(out, "    {}[\"{}\"] {{", id, table.title.replace('"', "'")).unwrap();
+        }
+        for field in &table.fields {
+            let name = mermaid_id(&field.title);
+            write!(out, "        {} {}", mermaid_id(&field.field_type), name).unwrap();
+            if let Some(marker) = key_marker(&field.name) {
+                write!(out, " {}", marker).unwrap();
+            }
+            if name != field.title {
+                write!(out, " \"{}\"", field.title.replace('"', "'")).unwrap();
+            }
+            out.push('\n');
+        }
+        out.push_str("    }\n");
+    }
+    for relationship in relationships {
+        if let (Some(parent), Some(child)) = (id_of(&relationship.parent_table), id_of(&relationship.child_table)) {
+            writeln!(out, "    {} ||--o{{ {} : \"{}\"", parent, child, relationship.child_key.replace('"', "'")).unwrap();
+        }
+    }
+    out
+}
+
+fn dot_string(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn xml_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+fn field_type_label(field_name: &str, field_type: &str) -> String {
+    match key_marker(field_name) {
+        Some(marker) => format!("{} {}", field_type, marker),
+        None => field_type.to_string(),
+    }
+}
+
+pub fn dot(tables: &[Table], relationships: &[Relationship]) -> String {
+    let mut out = String::from("digraph flatterer {\n    rankdir=BT;\n    node [shape=plain, fontname=\"monospace\"];\n    edge [fontname=\"monospace\", fontsize=10];\n");
+    for table in tables {
+        write!(
+            out,
+            "    {} [label=<<table border=\"0\" cellborder=\"1\" cellspacing=\"0\" cellpadding=\"4\"><tr><td colspan=\"2\" bgcolor=\"lightgrey\"><b>{}</b></td></tr>",
+            dot_string(&table.name),
+            xml_escape(&table.title)
+        )
+        .unwrap();
+        for field in &table.fields {
+            write!(
+                out,
+                "<tr><td align=\"left\">{}</td><td align=\"left\">{}</td></tr>",
+                xml_escape(&field.title),
+                xml_escape(&field_type_label(&field.name, &field.field_type))
+            )
+            .unwrap();
+        }
+        out.push_str("</table>>];\n");
+    }
+    for relationship in relationships {
+        writeln!(
+            out,
+            "    {} -> {} [label={}];",
+            dot_string(&relationship.child_table),
+            dot_string(&relationship.parent_table),
+            dot_string(&relationship.child_key)
+        )
+        .unwrap();
+    }
+    out.push_str("}\n");
+    out
+}
+
+const CHAR_WIDTH: f64 = 7.2;
+const ROW_HEIGHT: f64 = 20.0;
+const PADDING: f64 = 8.0;
+const GAP_X: f64 = 40.0;
+const GAP_Y: f64 = 60.0;
+
+struct TableBox {
+    x: f64,
+    y: f64,
+    width: f64,
+    height: f64,
+}
+
+/// Laid out without Graphviz: tables are put in rows by how many parents they have, so the
+/// main table is at the top and each level of child tables is a row below its parents.
+pub fn svg(tables: &[Table], relationships: &[Relationship]) -> String {
+    let level = |table: &Table| relationships.iter().filter(|relationship| relationship.child_table == table.name).count();
+    let max_level = tables.iter().map(level).max().unwrap_or(0);
+
+    let mut boxes: Vec<TableBox> = tables
+        .iter()
+        .map(|table| {
+            let name_width = table.fields.iter().map(|field| field.title.chars().count()).max().unwrap_or(0);
+            let type_width = table
+                .fields
+                .iter()
+                .map(|field| field_type_label(&field.name, &field.field_type).chars().count())
+                .max()
+                .unwrap_or(0);
+            let chars = (name_width + type_width + 2).max(table.title.chars().count());
+            TableBox {
+                x: 0.0,
+                y: 0.0,
+                width: chars as f64 * CHAR_WIDTH + 2.0 * PADDING,
+                height: (table.fields.len() + 1) as f64 * ROW_HEIGHT + PADDING,
+            }
+        })
+        .collect();
+
+    let (mut width, mut y) = (0.0_f64, GAP_X / 2.0);
+    for row in 0..=max_level {
+        let (mut x, mut row_height) = (GAP_X / 2.0, 0.0_f64);
+        for (table, table_box) in tables.iter().zip(boxes.iter_mut()) {
+            if level(table) == row {
+                table_box.x = x;
+                table_box.y = y;
+                x += table_box.width + GAP_X;
+                row_height = row_height.max(table_box.height);
+            }
+        }
+        width = width.max(x - GAP_X / 2.0);
+        if row_height > 0.0 {
+            y += row_height + GAP_Y;
+        }
+    }
+    let height = y - GAP_Y + GAP_X / 2.0;
+
+    let mut out = String::new();
+    writeln!(
+        out,
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{w:.0}\" height=\"{h:.0}\" viewBox=\"0 0 {w:.0} {h:.0}\" font-family=\"monospace\" font-size=\"12\">",
+        w = width.max(GAP_X),
+        h = height.max(GAP_X)
+    )
+    .unwrap();
+    out.push_str("<defs><marker id=\"arrow\" viewBox=\"0 0 10 10\" refX=\"10\" refY=\"5\" markerWidth=\"8\" markerHeight=\"8\" orient=\"auto\"><path d=\"M0,0 L10,5 L0,10 z\" fill=\"#555\"/></marker></defs>\n");
+
+    let box_of = |table_name: &str| tables.iter().position(|table| table.name == table_name).map(|index| &boxes[index]);
+    for relationship in relationships {
+        if let (Some(parent), Some(child)) = (box_of(&relationship.parent_table), box_of(&relationship.child_table)) {
+            writeln!(
+                out,
+                "<line x1=\"{:.1}\" y1=\"{:.1}\" x2=\"{:.1}\" y2=\"{:.1}\" stroke=\"#555\" marker-end=\"url(#arrow)\"><title>{}</title></line>",
+                child.x + child.width / 2.0,
+                child.y,
+                parent.x + parent.width / 2.0,
+                parent.y + parent.height,
+                xml_escape(&format!("{}.{} = {}.{}", relationship.child_title, relationship.child_key, relationship.parent_title, relationship.parent_key))
+            )
+            .unwrap();
+        }
+    }
+
+    for (table, table_box) in tables.iter().zip(&boxes) {
+        let TableBox { x, y, width, height } = *table_box;
+        writeln!(out, "<g>").unwrap();
+        writeln!(out, "<rect x=\"{:.1}\" y=\"{:.1}\" width=\"{:.1}\" height=\"{:.1}\" fill=\"white\" stroke=\"#333\"/>", x, y, width, height).unwrap();
+        writeln!(out, "<rect x=\"{:.1}\" y=\"{:.1}\" width=\"{:.1}\" height=\"{:.1}\" fill=\"#ddd\" stroke=\"#333\"/>", x, y, width, ROW_HEIGHT).unwrap();
+        writeln!(out, "<text x=\"{:.1}\" y=\"{:.1}\" font-weight=\"bold\">{}</text>", x + PADDING, y + ROW_HEIGHT - 6.0, xml_escape(&table.title)).unwrap();
+        for (index, field) in table.fields.iter().enumerate() {
+            let text_y = y + (index + 2) as f64 * ROW_HEIGHT - 6.0;
+            writeln!(out, "<text x=\"{:.1}\" y=\"{:.1}\">{}</text>", x + PADDING, text_y, xml_escape(&field.title)).unwrap();
+            writeln!(
+                out,
+                "<text x=\"{:.1}\" y=\"{:.1}\" text-anchor=\"end\" fill=\"#666\">{}</text>",
+                x + width - PADDING,
+                text_y,
+                xml_escape(&field_type_label(&field.name, &field.field_type))
+            )
+            .unwrap();
+        }
+        writeln!(out, "</g>").unwrap();
+    }
+    out.push_str("</svg>\n");
+    out
+}
+
+/// Write the diagrams of an output's tables into `DIAGRAM_DIR`, only the one for
+/// `diagram_format` if given.
+pub fn write_diagrams(output_path: &Path, diagram_format: Option<&str>) -> std::io::Result<PathBuf> {
+    let diagram_path = output_path.join(DIAGRAM_DIR);
+    std::fs::create_dir_all(&diagram_path)?;
+
+    let tables = relationships::read_tables(output_path)?;
+    let relationships = relationships::find(&tables);
+    for format in FORMATS {
+        if diagram_format.is_some_and(|diagram_format| diagram_format != format) {
+            continue;
+        }
+        let contents = match format {
+            "mermaid" => mermaid(&tables, &relationships),
+            "dot" => dot(&tables, &relationships),
+            _ => svg(&tables, &relationships),
+        };
+        let (file_name, _) = format_file(format).expect("known format");
+        std::fs::write(diagram_path.join(file_name), contents)?;
+    }
+    Ok(diagram_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relationships::Field;
+    use crate::tests::flatten_fixture;
+
+    fn fixture_tables() -> (Vec<Table>, Vec<Relationship>) {
+        let job_dir = flatten_fixture("basic.json");
+        let tables = relationships::read_tables(&job_dir.path().join("output")).unwrap();
+        let relationships = relationships::find(&tables);
+        (tables, relationships)
+    }
+
+    #[test]
+    fn fixture_mermaid() {
+        let (tables, relationships) = fixture_tables();
+        insta::assert_snapshot!(mermaid(&tables, &relationships));
+    }
+
+    #[test]
+    fn fixture_dot() {
+        let (tables, relationships) = fixture_tables();
+        insta::assert_snapshot!(dot(&tables, &relationships));
+    }
+
+    #[test]
+    fn fixture_svg() {
+        let (tables, relationships) = fixture_tables();
+        insta::assert_snapshot!(svg(&tables, &relationships));
+    }
+
+    #[test]
+    fn awkward_titles() {
+        let field = |name: &str, title: &str| Field { name: name.to_string(), title: title.to_string(), field_type: "text".to_string() };
+        let tables = vec![
+            Table { name: "main".to_string(), title: "1 Main <table> & \"quotes\"".to_string(), fields: vec![field("_link", "_link")] },
+            Table {
+                name: "items".to_string(),
+                title: "1 Main <table> & \"quotes\"".to_string(),
+                fields: vec![field("_link", "_link"), field("_link_main", "_link_main"), field("name", "Name & Title")],
+            },
+        ];
+        let relationships = relationships::find(&tables);
+        let diagrams = format!("{}\n{}", mermaid(&tables, &relationships), dot(&tables, &relationships));
+        insta::assert_snapshot!(diagrams);
+    }
+
+    #[test]
+    fn formats() {
+        let checked: Vec<(Option<&str>, Result<(), String>)> = [None, Some("mermaid"), Some("svg"), Some("png")]
+            .into_iter()
+            .map(|diagram_format| (diagram_format, check_format(diagram_format)))
+            .collect();
+        insta::assert_debug_snapshot!(checked);
+    }
+
+    #[test]
+    fn writes_only_the_asked_format() {
+        let job_dir = flatten_fixture("basic.json");
+        let diagram_path = write_diagrams(&job_dir.path().join("output"), Some("dot")).unwrap();
+        let files: Vec<String> = std::fs::read_dir(diagram_path)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        assert_eq!(files, vec!["diagram.dot"]);
+    }
+}
@@ -0,0 +1,121 @@
+use std::path::Path;
+
+/// Name of the DuckDB database written into the output.
+pub const DUCKDB_FILE: &str = "output.duckdb";
+
+/// Whether the server was built with the `duckdb` feature.
+pub const AVAILABLE: bool = cfg!(feature = "duckdb");
+
+/// Error for asking for DuckDB output when it is not `AVAILABLE`.
+pub const UNAVAILABLE: &str = "DuckDB output is not available, the server was built without the `duckdb` feature";
+
+#[cfg(feature = "duckdb")]
+fn quote(identifier: &str) -> String {
+    format!("\"{}\"", identifier.replace('"', "\"\""))
+}
+
+#[cfg(feature = "duckdb")]
+fn column_type(field_type: &str, datetime: bool) -> &'static str {
+    match field_type {
+        "number" => "DOUBLE",
+        "integer" => "BIGINT",
+        "boolean" => "BOOLEAN",
+        "date" if datetime => "TIMESTAMPTZ",
+        "date" => "DATE",
+        _ => "VARCHAR",
+    }
+}
+
+/// Write `output.duckdb` with a table for each CSV file of an output, its columns typed
+/// from `fields.csv` rather than guessed from the data.
+#[cfg(feature = "duckdb")]
+pub fn write_database(output_path: &Path) -> std::io::Result<()> {
+    use crate::{datapackage, relationships};
+
+    let db_path = output_path.join(DUCKDB_FILE);
+    if db_path.exists() {
+        std::fs::remove_file(&db_path)?;
+    }
+    let tables = relationships::output_tables(output_path)?;
+    let datetimes = datapackage::detected_datetimes(output_path);
+
+    let connection = duckdb::Connection::open(&db_path).map_err(std::io::Error::other)?;
+    let mut statements = String::from("BEGIN TRANSACTION;\n");
+    for table in &tables {
+        let columns: Vec<String> = table
+            .fields
+            .iter()
+            .map(|field| {
+                let datetime = datetimes.contains(&(table.name.clone(), field.name.clone()));
+                format!("{} {}", quote(&field.title), column_type(&field.field_type, datetime))
+            })
+            .collect();
+        let csv_path = output_path.join("csv").join(format!("{}.csv", table.title));
+        statements.push_str(&format!(
+            "CREATE TABLE {table} ({columns});\nCOPY {table} FROM '{path}' (FORMAT csv, HEADER true);\n",
+            table = quote(&table.title),
+            columns = columns.join(", "),
+            path = csv_path.to_string_lossy().replace('\'', "''"),
+        ));
+    }
+    statements.push_str("COMMIT;\n");
+    connection.execute_batch(&statements).map_err(std::io::Error::other)?;
+    log::info!(tables = tables.len() as u64; "duckdb written");
+    Ok(())
+}
+
+#[cfg(not(feature = "duckdb"))]
+pub fn write_database(_output_path: &Path) -> std::io::Result<()> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, UNAVAILABLE))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "duckdb")]
+    #[test]
+    fn database_tables() {
+        let job_dir = crate::tests::flatten_fixture("basic.json");
+        let output_path = job_dir.path().join("output");
+        write_database(&output_path).unwrap();
+
+        let connection = duckdb::Connection::open(output_path.join(DUCKDB_FILE)).unwrap();
+        let counts: Vec<(String, i64)> = ["main", "developer", "platforms"]
+            .into_iter()
+            .map(|table| {
+                let count = connection
+                    .query_row(&format!("SELECT count(*) FROM {}", quote(table)), [], |row| row.get(0))
+                    .unwrap();
+                (table.to_string(), count)
+            })
+            .collect();
+        assert_eq!(counts, [("main".to_string(), 2), ("developer".to_string(), 2), ("platforms".to_string(), 3)]);
+
+        let mut statement = connection
+            .prepare("SELECT column_name, data_type FROM information_schema.columns WHERE table_name = 'main' ORDER BY ordinal_position")
+            .unwrap();
+        let columns: Vec<(String, String)> = statement
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        let expected = [
+            ("_link", "VARCHAR"),
+            ("id", "DOUBLE"),
+            ("title", "VARCHAR"),
+            ("releaseDate", "DATE"),
+            ("rating_code", "VARCHAR"),
+            ("rating_name", "VARCHAR"),
+        ];
+        assert_eq!(columns, expected.map(|(name, column_type)| (name.to_string(), column_type.to_string())));
+    }
+
+    #[cfg(not(feature = "duckdb"))]
+    #[test]
+    fn database_unavailable() {
+        let error = write_database(Path::new("output")).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::Unsupported);
+        assert_eq!(error.to_string(), UNAVAILABLE);
+    }
+}
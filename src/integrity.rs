@@ -0,0 +1,148 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+use walkdir::WalkDir;
+
+/// Where a job's input came from and its checksum, kept in the job directory.
+const SOURCE_FILE: &str = "source.json";
+
+/// Written into each output with the checksums of the input and every output file.
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+pub fn sha256_file(path: &Path) -> std::io::Result<String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+pub fn timestamp(time: SystemTime) -> String {
+    humantime::format_rfc3339_seconds(time).to_string()
+}
+
+/// Record the name, size and SHA-256 of a job's freshly persisted `download.json`. `sha256`
+/// is computed when not already known.
+pub fn record_input(job_path: &Path, file_name: Option<&str>, sha256: Option<String>) -> std::io::Result<Value> {
+    let download_file = job_path.join("download.json");
+    let sha256 = match sha256 {
+        Some(sha256) => sha256,
+        None => sha256_file(&download_file)?,
+    };
+    let source = json!({
+        "file_name": file_name,
+        "size": download_file.metadata()?.len(),
+        "sha256": sha256,
+        "persisted_at": timestamp(SystemTime::now()),
+    });
+    std::fs::write(job_path.join(SOURCE_FILE), source.to_string())?;
+    log::info!(sha256 = source["sha256"].as_str(); "input checksum recorded");
+    Ok(source)
+}
+
+/// The recorded input of a job, recording it first for jobs made before it was persisted.
+pub fn input_record(job_path: &Path) -> std::io::Result<Value> {
+    let source: Option<Value> = File::open(job_path.join(SOURCE_FILE))
+        .ok()
+        .and_then(|file| serde_json::from_reader(BufReader::new(file)).ok());
+    match source {
+        Some(source) if source["sha256"].is_string() => Ok(source),
+        source => {
+            let file_name = source.as_ref().and_then(|source| source["file_name"].as_str());
+            record_input(job_path, file_name, None)
+        }
+    }
+}
+
+fn file_checksum(path: &Path) -> std::io::Result<Value> {
+    Ok(json!({"size": path.metadata()?.len(), "sha256": sha256_file(path)?}))
+}
+
+fn file_checksums(dir: &Path, paths: impl Iterator<Item = PathBuf>) -> std::io::Result<Map<String, Value>> {
+    let mut files = Map::new();
+    for path in paths {
+        let name = path.strip_prefix(dir).unwrap_or(&path).to_string_lossy().to_string();
+        files.insert(name, file_checksum(&path)?);
+    }
+    Ok(files)
+}
+
+/// Write `manifest.json` into `output_path`, recording the job's input, the options and
+/// versions used and the checksum of every output file. `metadata_files` are the files that
+/// changed what was output, such as `fields.csv`, which are recorded by file name if they exist.
+pub fn write_manifest(output_path: &Path, job_path: &Path, metadata_files: &[PathBuf], options: Value, started_at: SystemTime) -> std::io::Result<Value> {
+    let mut metadata_checksums = Map::new();
+    for path in metadata_files.iter().filter(|path| path.exists()) {
+        let name = path.file_name().unwrap_or(path.as_os_str()).to_string_lossy().to_string();
+        metadata_checksums.insert(name, file_checksum(path)?);
+    }
+
+    let output_files = WalkDir::new(output_path)
+        .min_depth(1)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file() && entry.file_name() != MANIFEST_FILE)
+        .map(|entry| entry.into_path());
+
+    let manifest = json!({
+        "flatterer_version": env!("LIBFLATTERER_VERSION"),
+        "flatterer_web_version": env!("CARGO_PKG_VERSION"),
+        "started_at": timestamp(started_at),
+        "finished_at": timestamp(SystemTime::now()),
+        "input": input_record(job_path)?,
+        "metadata_files": metadata_checksums,
+        "options": options,
+        "files": file_checksums(output_path, output_files)?,
+    });
+    std::fs::write(output_path.join(MANIFEST_FILE), serde_json::to_string_pretty(&manifest)?)?;
+    Ok(manifest)
+}
+
+pub fn read_manifest(output_path: &Path) -> Option<Value> {
+    let file = File::open(output_path.join(MANIFEST_FILE)).ok()?;
+    serde_json::from_reader(BufReader::new(file)).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// SHA-256 of `abc`, from FIPS 180-2.
+    const ABC_SHA256: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+    const EMPTY_SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+    #[test]
+    fn manifest_checksums() {
+        let job_dir = tempfile::tempdir().unwrap();
+        let output_dir = tempfile::tempdir().unwrap();
+        std::fs::write(job_dir.path().join("download.json"), "abc").unwrap();
+        std::fs::write(job_dir.path().join("fields.csv"), "").unwrap();
+        std::fs::create_dir(output_dir.path().join("csv")).unwrap();
+        std::fs::write(output_dir.path().join("csv").join("main.csv"), "abc").unwrap();
+        std::fs::write(output_dir.path().join("tables.csv"), "").unwrap();
+        record_input(job_dir.path(), Some("games.json"), None).unwrap();
+
+        let started_at = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000);
+        let metadata_files = [job_dir.path().join("fields.csv"), job_dir.path().join("tables.csv")];
+        let written = write_manifest(output_dir.path(), job_dir.path(), &metadata_files, json!({"output_format": "csv"}), started_at).unwrap();
+        let manifest = read_manifest(output_dir.path()).unwrap();
+        assert_eq!(manifest, written);
+
+        assert_eq!(manifest["input"]["sha256"], ABC_SHA256);
+        assert_eq!(manifest["files"]["csv/main.csv"]["sha256"], ABC_SHA256);
+        assert_eq!(manifest["files"]["tables.csv"]["sha256"], EMPTY_SHA256);
+        // Every output file is listed, apart from the manifest itself.
+        for (name, file) in manifest["files"].as_object().unwrap() {
+            assert_eq!(file["sha256"], sha256_file(&output_dir.path().join(name)).unwrap());
+        }
+        insta::assert_yaml_snapshot!(manifest, {
+            ".flatterer_version" => "[version]",
+            ".flatterer_web_version" => "[version]",
+            ".finished_at" => "[timestamp]",
+            ".input.persisted_at" => "[timestamp]",
+        });
+    }
+}
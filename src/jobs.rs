@@ -0,0 +1,231 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use actix_web::dev::ServerHandle;
+use serde_json::json;
+
+/// Status of a job's latest conversion, also written to `job.json` in the job directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobStatus {
+    Running,
+    Finished,
+    Failed,
+}
+
+impl JobStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            JobStatus::Running => "running",
+            JobStatus::Finished => "finished",
+            JobStatus::Failed => "failed",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct RunningJob {
+    job_id: String,
+    job_dir: PathBuf,
+    tmp_dir: PathBuf,
+}
+
+/// Keeps track of conversions in progress so they can be drained on shutdown.
+#[derive(Debug)]
+pub struct JobStore {
+    accepting: AtomicBool,
+    next_run: AtomicU64,
+    running: Mutex<HashMap<u64, RunningJob>>,
+}
+
+impl Default for JobStore {
+    fn default() -> Self {
+        JobStore {
+            accepting: AtomicBool::new(true),
+            next_run: AtomicU64::new(0),
+            running: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+fn write_status(job_dir: &Path, status: JobStatus, error: Option<&str>) {
+    let status_json = json!({"status": status.as_str(), "error": error});
+    if let Err(err) = std::fs::write(job_dir.join("job.json"), status_json.to_string()) {
+        log::warn!(error = err.to_string(); "could not write job status");
+    }
+}
+
+impl JobStore {
+    pub fn accepting(&self) -> bool {
+        self.accepting.load(Ordering::SeqCst)
+    }
+
+    pub fn stop_accepting(&self) {
+        self.accepting.store(false, Ordering::SeqCst);
+    }
+
+    pub fn running_count(&self) -> usize {
+        self.running.lock().expect("job store lock poisoned").len()
+    }
+
+    /// Register a conversion, returning `None` if the server is shutting down.
+    pub fn start(self: &Arc<Self>, job_id: &str, job_dir: &Path, tmp_dir: &Path) -> Option<JobGuard> {
+        if !self.accepting() {
+            return None;
+        }
+        let run = self.next_run.fetch_add(1, Ordering::SeqCst);
+        write_status(job_dir, JobStatus::Running, None);
+        self.running.lock().expect("job store lock poisoned").insert(
+            run,
+            RunningJob {
+                job_id: job_id.to_string(),
+                job_dir: job_dir.to_path_buf(),
+                tmp_dir: tmp_dir.to_path_buf(),
+            },
+        );
+        Some(JobGuard { store: self.clone(), run, finished: false })
+    }
+
+    /// Mark every conversion still running as failed and remove its temporary output directory.
+    pub fn fail_unfinished(&self) {
+        let running: Vec<RunningJob> = self
+            .running
+            .lock()
+            .expect("job store lock poisoned")
+            .drain()
+            .map(|(_, job)| job)
+            .collect();
+
+        for job in running {
+            log::warn!(job_id = job.job_id; "job did not finish before shutdown");
+            write_status(&job.job_dir, JobStatus::Failed, Some("server shut down before the job finished"));
+            if let Err(err) = std::fs::remove_dir_all(&job.tmp_dir) {
+                log::warn!(job_id = job.job_id, error = err.to_string(); "could not remove output directory");
+            }
+        }
+    }
+}
+
+/// A registered conversion, removed from the store when finished. Dropping it unfinished,
+/// as happens when the client goes away mid-job, marks the job as cancelled.
+pub struct JobGuard {
+    store: Arc<JobStore>,
+    run: u64,
+    finished: bool,
+}
+
+impl JobGuard {
+    pub fn finish(mut self, error: Option<&str>) {
+        self.complete(error);
+    }
+
+    fn complete(&mut self, error: Option<&str>) {
+        self.finished = true;
+        let job = self.store.running.lock().expect("job store lock poisoned").remove(&self.run);
+        if let Some(job) = job {
+            let status = if error.is_some() { JobStatus::Failed } else { JobStatus::Finished };
+            write_status(&job.job_dir, status, error);
+        }
+    }
+}
+
+impl Drop for JobGuard {
+    fn drop(&mut self) {
+        if !self.finished {
+            log::warn!("job cancelled before it finished");
+            self.complete(Some("cancelled"));
+        }
+    }
+}
+
+/// Wait for SIGTERM or Ctrl-C, then stop taking new jobs and give running ones until
+/// `deadline` to finish before they are terminated.
+pub async fn shutdown_on_signal(server: ServerHandle, jobs: Arc<JobStore>, deadline: Duration) {
+    wait_for_signal().await;
+
+    log::info!(running = jobs.running_count() as u64, deadline_secs = deadline.as_secs(); "shutting down");
+    jobs.stop_accepting();
+
+    actix_web::rt::spawn(async move {
+        actix_web::rt::time::sleep(deadline).await;
+        libflatterer::TERMINATE.store(true, Ordering::SeqCst);
+    });
+
+    server.stop(true).await;
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate()).expect("could not listen for SIGTERM");
+    tokio::select! {
+        _ = terminate.recv() => {},
+        _ = tokio::signal::ctrl_c() => {},
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(job_dir: &Path) -> serde_json::Value {
+        serde_json::from_str(&std::fs::read_to_string(job_dir.join("job.json")).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn job_statuses() {
+        let jobs = Arc::new(JobStore::default());
+        let dirs: Vec<tempfile::TempDir> = (0..4).map(|_| tempfile::tempdir().unwrap()).collect();
+        let tmp_dir = tempfile::tempdir().unwrap();
+
+        let finished = jobs.start("finished", dirs[0].path(), tmp_dir.path()).unwrap();
+        let failed = jobs.start("failed", dirs[1].path(), tmp_dir.path()).unwrap();
+        let cancelled = jobs.start("cancelled", dirs[2].path(), tmp_dir.path()).unwrap();
+        assert_eq!(jobs.running_count(), 3);
+        assert_eq!(status(dirs[0].path()), json!({"status": "running", "error": null}));
+
+        finished.finish(None);
+        failed.finish(Some("bad input"));
+        drop(cancelled);
+        assert_eq!(jobs.running_count(), 0);
+
+        let statuses: Vec<serde_json::Value> = dirs[..3].iter().map(|dir| status(dir.path())).collect();
+        assert_eq!(
+            statuses,
+            vec![
+                json!({"status": "finished", "error": null}),
+                json!({"status": "failed", "error": "bad input"}),
+                json!({"status": "failed", "error": "cancelled"}),
+            ]
+        );
+    }
+
+    #[test]
+    fn unfinished_at_shutdown() {
+        let jobs = Arc::new(JobStore::default());
+        let job_dir = tempfile::tempdir().unwrap();
+        let tmp_dir = tempfile::tempdir().unwrap();
+        std::fs::write(tmp_dir.path().join("partial.csv"), "a,b").unwrap();
+
+        let unfinished = jobs.start("unfinished", job_dir.path(), tmp_dir.path()).unwrap();
+        jobs.stop_accepting();
+        assert!(jobs.start("late", job_dir.path(), tmp_dir.path()).is_none());
+
+        jobs.fail_unfinished();
+        assert_eq!(jobs.running_count(), 0);
+        assert_eq!(status(job_dir.path()), json!({"status": "failed", "error": "server shut down before the job finished"}));
+        assert!(!tmp_dir.path().exists());
+
+        // Finishing after shutdown leaves the failed status alone.
+        unfinished.finish(None);
+        assert_eq!(status(job_dir.path())["status"], "failed");
+    }
+}
@@ -0,0 +1,83 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use serde_json::{Map, Value};
+
+use crate::relationships::{self, Table};
+use crate::typed;
+
+/// Directory within the output the tables are written to for `output_format`, and the
+/// extension of their files, or `None` if it is not `json` or `ndjson`.
+pub fn format_dir(output_format: &str) -> Option<(&'static str, &'static str)> {
+    match output_format {
+        "json" => Some(("json", "json")),
+        "ndjson" => Some(("ndjson", "ndjson")),
+        _ => None,
+    }
+}
+
+fn write_table(output_path: &Path, table: &Table, path: &Path, ndjson: bool) -> std::io::Result<u64> {
+    let mut reader = csv::Reader::from_path(output_path.join("csv").join(format!("{}.csv", table.title)))?;
+    let mut writer = BufWriter::new(File::create(path)?);
+    if !ndjson {
+        writer.write_all(b"[")?;
+    }
+
+    let mut rows = 0;
+    for record in reader.records() {
+        let record = record?;
+        let object: Map<String, Value> = table
+            .fields
+            .iter()
+            .zip(record.iter())
+            .map(|(field, value)| (field.title.clone(), typed::typed_value(value, &field.field_type)))
+            .collect();
+        if ndjson {
+            serde_json::to_writer(&mut writer, &object)?;
+            writer.write_all(b"\n")?;
+        } else {
+            writer.write_all(if rows == 0 { &b"\n"[..] } else { &b",\n"[..] })?;
+            serde_json::to_writer(&mut writer, &object)?;
+        }
+        rows += 1;
+    }
+
+    if !ndjson {
+        writer.write_all(b"\n]\n")?;
+    }
+    writer.flush()?;
+    Ok(rows)
+}
+
+/// Write each CSV file of an output as a JSON array of objects, or for `ndjson` an object
+/// per line, keyed by the same column titles and with cells typed from `fields.csv`.
+pub fn write_tables(output_path: &Path, output_format: &str) -> std::io::Result<PathBuf> {
+    let (dir, extension) = format_dir(output_format).expect("json or ndjson");
+    let json_path = output_path.join(dir);
+    std::fs::create_dir_all(&json_path)?;
+
+    for table in relationships::output_tables(output_path)? {
+        let rows = write_table(output_path, &table, &json_path.join(format!("{}.{}", table.title, extension)), extension == "ndjson")?;
+        log::info!(table = table.title.as_str(), rows = rows; "json table written");
+    }
+    Ok(json_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn typed_tables() {
+        let job_dir = crate::tests::flatten_fixture("typed.json");
+        let output_path = job_dir.path().join("output");
+        for output_format in ["json", "ndjson"] {
+            let json_path = write_tables(&output_path, output_format).unwrap();
+            for table in ["main", "tags"] {
+                let contents = std::fs::read_to_string(json_path.join(format!("{}.{}", table, output_format))).unwrap();
+                insta::assert_snapshot!(format!("{}_{}", output_format, table), contents);
+            }
+        }
+    }
+}
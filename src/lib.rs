@@ -1,22 +1,47 @@
-use actix_web::{web::{self}, App, Either, HttpResponse, HttpServer, Responder, Result};
-use futures::StreamExt;
+use actix_web::{web::{self}, App, Either, FromRequest, HttpRequest, HttpResponse, HttpServer, Responder};
+use futures::{SinkExt, StreamExt};
 use actix_web::body::BoxBody;
 use csv::Reader;
 use actix_files::Files;
 use std::fs::File;
-use std::{collections::HashMap, env::var};
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::Arc;
+use std::time::Duration;
 use walkdir::WalkDir;
-use actix_web::middleware::Logger;
+use actix_web::middleware::from_fn;
+use actix_web::http::header::{Accept, ContentDisposition, DispositionParam, DispositionType, Header, HeaderName, HeaderValue, Quality};
 use serde::Deserialize;
 use tempfile::TempDir;
 use uuid::Uuid;
 use serde_json::{json, Value};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use actix_multipart::form::tempfile::TempFile;
-use actix_multipart::form::MultipartForm;
-use std::io::Read;
+use actix_multipart::form::{MultipartForm, MultipartFormConfig};
+use std::io::{Read, Write};
 use libflatterer::{flatten, Options};
 
+pub mod batch;
+pub mod config;
+mod datapackage;
+mod destination;
+mod diagram;
+mod duckdb_output;
+mod integrity;
+mod jobs;
+mod json_output;
+mod logging;
+mod presets;
+mod relationships;
+mod sql;
+mod tls;
+mod typed;
+mod uploads;
+mod validation;
+mod xlsx;
+
+use config::Config;
+use jobs::JobStore;
 
 #[derive(Debug, MultipartForm)]
 struct UploadForm {
@@ -26,9 +51,14 @@ struct UploadForm {
     fields: Option<TempFile>,
     #[multipart(limit = "100MB")]
     tables: Option<TempFile>,
+    #[multipart(limit = "100MB")]
+    schema: Option<TempFile>,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+/// The `limit` of each part of `UploadForm`, which a raw request body is held to as well.
+const UPLOAD_PART_LIMIT: u64 = 100_000_000;
+
+#[derive(Deserialize, Debug, Clone, Default)]
 struct Query {
     id: Option<String>,
     output_format: Option<String>,
@@ -44,6 +74,119 @@ struct Query {
     fields_only: Option<bool>,
     tables_only: Option<bool>,
     pushdown: Option<String>,
+    #[serde(alias = "limit")]
+    preview_rows: Option<usize>,
+    table: Option<String>,
+    offset: Option<usize>,
+    preview_format: Option<String>,
+    validate: Option<bool>,
+    preset: Option<String>,
+    /// File name to serve a download as.
+    filename: Option<String>,
+    /// `mermaid`, `dot` or `svg` to get only that diagram rather than a zip of all three.
+    diagram_format: Option<String>,
+    /// Configured database `output_format=postgres` loads the tables into.
+    destination: Option<String>,
+    destination_schema: Option<String>,
+    /// `append` (the default) or `replace`.
+    destination_mode: Option<String>,
+    /// Add `output.duckdb` to the zip.
+    duckdb: Option<bool>,
+    /// Keep the header row of each sheet in view, on by default.
+    xlsx_freeze_header: Option<bool>,
+    /// Size columns to their contents, on by default.
+    xlsx_autofit: Option<bool>,
+    /// Add a `README` sheet describing each table and field.
+    xlsx_readme: Option<bool>,
+    /// Continue tables with more rows than fit in a sheet on further sheets.
+    xlsx_split: Option<bool>,
+    /// The `fields.csv` and `tables.csv` of a preset, flattened with in place of the job's.
+    /// Set by `presets::apply` rather than by the request.
+    #[serde(skip)]
+    fields_csv: Option<PathBuf>,
+    #[serde(skip)]
+    tables_csv: Option<PathBuf>,
+}
+
+const DEFAULT_PREVIEW_ROWS: usize = 10;
+const MAX_PREVIEW_ROWS: usize = 1000;
+/// Rows of each table kept when flattening for a preview, later pages need a full flatten.
+const PREVIEW_OUTPUT_ROWS: usize = MAX_PREVIEW_ROWS;
+
+impl Query {
+    /// Identifies the options that change what gets flattened, so a full output can be reused.
+    fn flatten_key(&self) -> String {
+        format!(
+            "{:?}",
+            (
+                &self.array_key,
+                &self.json_lines,
+                &self.main_table_name,
+                &self.inline_one_to_one,
+                &self.json_schema,
+                &self.table_prefix,
+                &self.path_separator,
+                &self.schema_titles,
+                &self.fields_only,
+                &self.tables_only,
+                &self.pushdown,
+                &self.validate,
+            )
+        )
+    }
+
+    /// The options used for a conversion, as recorded in its manifest.
+    fn options_json(&self) -> Value {
+        let mut options = json!(presets::PresetOptions::from_query(self));
+        options["output_format"] = json!(self.output_format.as_deref().unwrap_or("zip"));
+        options
+    }
+
+    /// Whether the output is or includes a DuckDB database.
+    fn wants_duckdb(&self) -> bool {
+        match self.output_format.as_deref() {
+            Some("duckdb") => true,
+            None | Some("zip") => self.duckdb.unwrap_or(false),
+            _ => false,
+        }
+    }
+
+    /// The `fields.csv`, `tables.csv` and `schema.json` a job is flattened with, which may not exist.
+    fn metadata_files(&self, job_path: &Path) -> [PathBuf; 3] {
+        [
+            self.fields_csv.clone().unwrap_or_else(|| job_path.join("fields.csv")),
+            self.tables_csv.clone().unwrap_or_else(|| job_path.join("tables.csv")),
+            job_path.join("schema.json"),
+        ]
+    }
+
+    fn row_window(&self) -> Result<RowWindow, String> {
+        let rows = self.preview_rows.unwrap_or(DEFAULT_PREVIEW_ROWS);
+        if rows > MAX_PREVIEW_ROWS {
+            return Err(format!("`preview_rows` can be at most {}", MAX_PREVIEW_ROWS));
+        }
+        let as_rows = match self.preview_format.as_deref() {
+            None | Some("fields") => false,
+            Some("rows") => true,
+            Some(other) => return Err(format!("unknown `preview_format` `{}`, use `fields` or `rows`", other)),
+        };
+        Ok(RowWindow {
+            table: self.table.clone(),
+            offset: self.offset.unwrap_or(0),
+            rows,
+            as_rows,
+        })
+    }
+}
+
+/// Which rows of which tables to return in a preview.
+#[derive(Debug, Clone)]
+struct RowWindow {
+    table: Option<String>,
+    offset: usize,
+    rows: usize,
+    /// Return `columns` and typed `rows` rather than rows pivoted into each field.
+    as_rows: bool,
 }
 
 fn run_flatterer(
@@ -52,11 +195,25 @@ fn run_flatterer(
     output_path: PathBuf,
     json_lines: bool,
     path: String,
-) -> Result<()> {
+) -> std::io::Result<()> {
     let file = std::fs::File::open(download_path.join("download.json"))?;
     let reader = std::io::BufReader::new(file);
 
+    let options_json = query.options_json();
+    let [fields_path, tables_path, schema_path] = query.metadata_files(&download_path);
+    let xlsx_options = xlsx::XlsxOptions::from_query(&query);
+    let started_at = std::time::SystemTime::now();
+    let duckdb = query.wants_duckdb();
     let output_format = query.output_format.unwrap_or_else(|| "zip".to_string());
+    let validate = query.validate.unwrap_or(false);
+    let diagram_format = query.diagram_format.filter(|_| output_format == "diagram");
+    if duckdb && !duckdb_output::AVAILABLE {
+        return Err(std::io::Error::new(std::io::ErrorKind::Unsupported, duckdb_output::UNAVAILABLE));
+    }
+    diagram::check_format(diagram_format.as_deref()).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+
+    log::info!(output_format = output_format, json_lines = json_lines; "flatten started");
+    let start = std::time::Instant::now();
 
     let mut options = Options::builder().build();
 
@@ -69,7 +226,7 @@ fn run_flatterer(
     if output_format == "xlsx" {
         options.xlsx = true;
     }
-    if output_format == "csv" {
+    if matches!(output_format.as_str(), "csv" | "datapackage" | "sql" | "postgres" | "duckdb" | "json" | "ndjson") {
         options.csv = true;
     }
     if output_format == "sqlite" {
@@ -77,33 +234,60 @@ fn run_flatterer(
     }
     if output_format == "preview" {
         options.csv = true;
-        options.preview = 10;
+        options.preview = PREVIEW_OUTPUT_ROWS;
+    }
+    // The workbook is made from the CSV files once everything else is written, rather than
+    // by libflatterer, so it can have the `xlsx_*` options and any validation table.
+    let xlsx = options.xlsx;
+    if xlsx {
+        options.xlsx = false;
+        options.csv = true;
     }
     options.force = true;
     options.main_table_name = query.main_table_name.unwrap_or_else(|| "main".to_string());
 
     options.inline_one_to_one = query.inline_one_to_one.unwrap_or(false);
 
-    options.schema = query.json_schema.unwrap_or_else(|| "".to_string());
+    options.schema = match query.json_schema {
+        Some(json_schema) if !json_schema.is_empty() => json_schema,
+        _ if schema_path.exists() => {
+            validate_schema(&schema_path).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+            schema_path.to_string_lossy().into()
+        }
+        _ => "".to_string(),
+    };
+
+    let validation_schema = if validate {
+        if options.schema.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "`validate` needs a JSON Schema, upload a schema or set `json_schema`",
+            ));
+        }
+        let schema = validation::load_schema(&options.schema)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        Some(schema)
+    } else {
+        None
+    };
 
-    options.table_prefix = query.table_prefix.unwrap_or_else(|| "".to_string());
+    let table_prefix = query.table_prefix.unwrap_or_default();
+    options.table_prefix = table_prefix.clone();
     options.path_separator = query.path_separator.unwrap_or_else(|| "_".to_string());
-    options.schema_titles = query.schema_titles.unwrap_or_else(|| "".to_string());
+    options.schema_titles = query.schema_titles.unwrap_or_default();
     options.json_stream = json_lines;
 
-    let fields_path = download_path.join("fields.csv");
     if fields_path.exists() {
         options.fields_csv = fields_path.to_string_lossy().into();
     }
-    options.only_fields = query.fields_only.unwrap_or_else(|| false);
+    options.only_fields = query.fields_only.unwrap_or(false);
 
-    let tables_path = download_path.join("tables.csv");
     if tables_path.exists() {
         options.tables_csv = tables_path.to_string_lossy().into();
     }
-    options.only_tables = query.tables_only.unwrap_or_else(|| false);
+    options.only_tables = query.tables_only.unwrap_or(false);
 
-    let pushdown = query.pushdown.unwrap_or_else(|| "".into());
+    let pushdown = query.pushdown.unwrap_or_default();
     if !pushdown.is_empty() {
         options.pushdown = vec![pushdown];
     }
@@ -111,19 +295,103 @@ fn run_flatterer(
     let mut path_vec = vec![];
 
     if !path.is_empty() && !json_lines {
-        path_vec.push(path);
+        path_vec.push(path.clone());
     }
     options.path = path_vec;
 
+    let (schema_location, sqlite) = (options.schema.clone(), options.sqlite);
+
     flatten(
         Box::new(reader),
         output_path.to_string_lossy().to_string(),
         options
-    ).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+    ).map_err(std::io::Error::other)?;
+
+    if let Some(schema) = validation_schema {
+        let report = validation::validate_input(&download_path.join("download.json"), &schema, &path, json_lines)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        log::info!(
+            records = report.records as u64,
+            invalid_records = report.invalid_records as u64,
+            errors = report.error_count as u64;
+            "validation finished"
+        );
+        validation::write_report(&output_path, &report, &table_prefix, sqlite)?;
+    }
+
+    relationships::write_csv(&output_path)?;
+    if output_format == "sql" {
+        sql::write_scripts(&output_path)?;
+    }
+    if duckdb {
+        duckdb_output::write_database(&output_path)?;
+    }
+    if xlsx {
+        let schema = if xlsx_options.readme && !schema_location.is_empty() {
+            Some(validation::load_schema(&schema_location).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?)
+        } else {
+            None
+        };
+        xlsx::write_workbook(&output_path, &xlsx_options, schema.as_ref())?;
+    }
+
+    // The package, diagrams and JSON tables are downloaded on their own so they get their own manifest.
+    let manifest_path = match output_format.as_str() {
+        "datapackage" => datapackage::write_package(&output_path)?,
+        "diagram" => diagram::write_diagrams(&output_path, diagram_format.as_deref())?,
+        "json" | "ndjson" => json_output::write_tables(&output_path, &output_format)?,
+        _ => output_path.clone(),
+    };
+    integrity::write_manifest(&manifest_path, &download_path, &[fields_path, tables_path, schema_path], options_json, started_at)?;
+
+    log::info!(
+        tables = table_count(&output_path).unwrap_or(0),
+        elapsed_ms = start.elapsed().as_millis() as u64;
+        "flatten finished"
+    );
     Ok(())
 }
 
-async fn download(url_string: String, tmp_dir: PathBuf) -> eyre::Result<()> {
+/// Runs `run_flatterer` on its own thread so workers are not blocked and shutdown is not
+/// held up by a conversion that outlives its deadline.
+async fn run_flatterer_in_thread(
+    query: Query,
+    download_path: PathBuf,
+    output_path: PathBuf,
+    json_lines: bool,
+    path: String,
+) -> std::io::Result<()> {
+    run_in_thread(move || run_flatterer(query, download_path, output_path, json_lines, path)).await
+}
+
+/// Runs blocking work on its own thread, keeping the job's log context.
+async fn run_in_thread<T: Send + 'static>(work: impl FnOnce() -> std::io::Result<T> + Send + 'static) -> std::io::Result<T> {
+    let (sender, receiver) = futures::channel::oneshot::channel();
+    let log_context = logging::current_context();
+    std::thread::spawn(move || {
+        let result = logging::sync_scope(log_context, work);
+        let _ = sender.send(result);
+    });
+    receiver.await.unwrap_or_else(|_| Err(std::io::Error::other("worker thread stopped unexpectedly")))
+}
+
+/// Check an uploaded schema is a JSON object before handing it to libflatterer.
+fn validate_schema(schema_path: &Path) -> Result<(), String> {
+    let file = File::open(schema_path).map_err(|e| format!("could not read schema: {}", e))?;
+    let schema: Value = serde_json::from_reader(std::io::BufReader::new(file))
+        .map_err(|e| format!("schema is not valid JSON: {}", e))?;
+    if !schema.is_object() {
+        return Err("schema must be a JSON object".to_string());
+    }
+    Ok(())
+}
+
+fn table_count(output_path: &std::path::Path) -> csv::Result<usize> {
+    let mut tables_reader = Reader::from_path(output_path.join("tables.csv"))?;
+    Ok(tables_reader.records().count())
+}
+
+async fn download(url_string: String, tmp_dir: PathBuf, max_size: u64) -> eyre::Result<()> {
 
     if !url_string.starts_with("http") {
         // return Err(tide::Error::from_str(tide::StatusCode::BadRequest, "`url` is empty or does not start with `http`"))
@@ -131,14 +399,26 @@ async fn download(url_string: String, tmp_dir: PathBuf) -> eyre::Result<()> {
     }
     let download_file = tmp_dir.join("download.json");
 
-    let mut file = tokio::fs::File::create(&download_file).await.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+    log::info!(url = url_string; "download started");
 
-    let mut stream = reqwest::get(&url_string).await.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?.bytes_stream();
+    let mut file = tokio::fs::File::create(&download_file).await.map_err(std::io::Error::other)?;
 
+    let mut stream = reqwest::get(&url_string)
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(std::io::Error::other)?
+        .bytes_stream();
+
+    let mut bytes = 0;
     while let Some(item) = stream.next().await {
-        tokio::io::copy(&mut item?.as_ref(), &mut file).await?;
+        bytes += tokio::io::copy(&mut item?.as_ref(), &mut file).await?;
+        if bytes > max_size {
+            return Err(eyre::eyre!("Download Size Exceeded, maximum allowed is {} bytes", max_size))
+        }
     }
 
+    log::info!(url = url_string, bytes = bytes; "download finished");
+
     Ok(())
 }
 
@@ -154,7 +434,23 @@ fn fields_output(output_path: PathBuf) -> csv::Result<Vec<HashMap<String, String
     Ok(all_fields)
 }
 
-async fn preview_output(output_path: PathBuf, fields: Vec<HashMap<String, String>>) -> csv::Result<Value> {
+/// Reads the rows of a table's CSV within `offset` and `offset + rows`.
+fn table_rows(path: &Path, offset: usize, rows: usize) -> csv::Result<Vec<Vec<String>>> {
+    let reader = Reader::from_path(path)?;
+    reader.into_deserialize().skip(offset).take(rows).collect()
+}
+
+/// The number of rows in a table, which is the most any of its fields are counted in `fields.csv`.
+/// Unlike the CSV this is for the whole input, even when only a preview was written.
+fn row_count(table_fields: &[HashMap<String, String>]) -> usize {
+    table_fields
+        .iter()
+        .filter_map(|field| field.get("count")?.parse::<usize>().ok())
+        .max()
+        .unwrap_or(0)
+}
+
+async fn preview_output(output_path: PathBuf, fields: Vec<HashMap<String, String>>, window: RowWindow) -> csv::Result<Value> {
     let mut previews = vec![];
 
     let mut tables_reader = Reader::from_path(output_path.join("tables.csv"))?;
@@ -164,7 +460,16 @@ async fn preview_output(output_path: PathBuf, fields: Vec<HashMap<String, String
         let table = table_row.get("table_name").unwrap().clone();
         let table_title = table_row.get("table_title").unwrap().clone();
 
+        if let Some(only_table) = &window.table {
+            if only_table != &table && only_table != &table_title {
+                continue;
+            }
+        }
+
         let path = output_path.join("csv").join(format!("{}.csv", table_title));
+        if !path.exists() {
+            continue;
+        }
 
         let mut table_fields = vec![];
 
@@ -174,22 +479,40 @@ async fn preview_output(output_path: PathBuf, fields: Vec<HashMap<String, String
             }
         }
 
-        let mut reader = Reader::from_path(path)?;
-        for (row_num, row) in reader.deserialize().enumerate() {
-            let row: Vec<String> = row?;
+        let rows = table_rows(&path, window.offset, window.rows)?;
+        let row_count = row_count(&table_fields);
+
+        if window.as_rows {
+            let preview = json!({
+                "table_name": table_title,
+                "columns": typed::columns(&table_fields),
+                "rows": typed::typed_rows(&rows, &table_fields),
+                "row_count": row_count,
+                "offset": window.offset,
+            });
+            previews.push(preview);
+            continue;
+        }
+
+        for (row_num, row) in rows.iter().enumerate() {
+            if row.len() != table_fields.len() {
+                log::warn!(table = table_title, row = row_num, columns = row.len(), fields = table_fields.len(); "row does not match fields");
+            }
             for (col_num, item) in row.iter().enumerate(){
-                table_fields[col_num].insert(format!("row {}", row_num), item.clone());
+                if let Some(field) = table_fields.get_mut(col_num) {
+                    field.insert(format!("row {}", row_num), item.clone());
+                }
             }
         }
 
-        let preview = json!({"table_name": table_title, "fields": table_fields});
+        let preview = json!({"table_name": table_title, "fields": table_fields, "row_count": row_count, "offset": window.offset});
 
         previews.push(preview);
     }
     Ok(serde_json::to_value(previews).expect("should not have issue converting to value"))
 }
 
-fn zip_output(output_path: PathBuf, tmp_dir_path: PathBuf) -> Result<()> {
+fn zip_output(output_path: PathBuf, tmp_dir_path: PathBuf) -> std::io::Result<()> {
     let zip_file = tmp_dir_path.join("export.zip");
 
     let file = File::create(&zip_file)?;
@@ -208,12 +531,12 @@ fn zip_output(output_path: PathBuf, tmp_dir_path: PathBuf) -> Result<()> {
             zip.add_directory(
                 path.strip_prefix(output_path.clone()).expect("known to be a directory").to_string_lossy(),
                 options,
-            ).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+            ).map_err(std::io::Error::other)?;
         } else {
             zip.start_file(
                 path.strip_prefix(output_path.clone()).expect("known to be a file").to_string_lossy(),
                 options,
-            ).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+            ).map_err(std::io::Error::other)?;
             let mut file = File::open(path)?;
             std::io::copy(&mut file, &mut zip)?;
         }
@@ -223,86 +546,348 @@ fn zip_output(output_path: PathBuf, tmp_dir_path: PathBuf) -> Result<()> {
 
 
 fn internal_error_json(error: String) -> HttpResponse<BoxBody> {
+    log::error!(error = error; "internal error");
     HttpResponse::InternalServerError().body(json!({"error": error}).to_string())
 }
 
+fn unavailable_json(error: &str) -> HttpResponse<BoxBody> {
+    log::warn!(error = error; "service unavailable");
+    HttpResponse::ServiceUnavailable().body(json!({"error": error}).to_string())
+}
+
+fn not_found_json(error: &str) -> HttpResponse<BoxBody> {
+    log::warn!(error = error; "not found");
+    HttpResponse::NotFound().body(json!({"error": error}).to_string())
+}
+
+fn conflict_json(error_json: Value) -> HttpResponse<BoxBody> {
+    if let Some(error) = error_json.get("error") {
+        log::warn!(error = error.as_str().unwrap_or_default(); "conflict");
+    }
+    HttpResponse::Conflict().body(error_json.to_string())
+}
+
 fn bad_request_json(error_json: Value) -> HttpResponse<BoxBody> {
+    if let Some(error) = error_json.get("error") {
+        log::warn!(error = error.as_str().unwrap_or_default(); "bad request");
+    }
     HttpResponse::BadRequest().body(error_json.to_string())
 }
 
-async fn convert(query: web::Query<Query>) -> Either<HttpResponse<BoxBody>, impl Responder> {
-    process(query, None).await
+/// Input sent with a request to `/api/get_input`.
+enum Upload {
+    Form(Box<UploadForm>),
+    /// The request body is the input itself, gzipped when `gzip` is set.
+    Body { payload: web::Payload, gzip: bool },
+}
+
+/// Response header with the id of the job, to fetch other outputs of the same input.
+const JOB_ID_HEADER: &str = "x-job-id";
+
+const XLSX_MIME: &str = "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet";
+
+/// Media types that can be asked for in an `Accept` header, with the output format of each.
+/// Zip comes first as the default format, so it is used for `*/*` and `application/*`.
+const ACCEPT_FORMATS: [(&str, &str); 5] = [
+    ("application/zip", "zip"),
+    (XLSX_MIME, "xlsx"),
+    ("application/vnd.sqlite3", "sqlite"),
+    ("application/x-sqlite3", "sqlite"),
+    ("text/csv", "csv"),
+];
+
+/// The output format for a media type, or media range such as `text/*`, in an `Accept`
+/// header, apart from those of the `refused` media types.
+fn format_for_mime(mime: &mime::Mime, refused: &[&mime::Mime]) -> Option<&'static str> {
+    ACCEPT_FORMATS
+        .iter()
+        .find(|(known, _)| {
+            let (type_, subtype) = known.split_once('/').expect("known media types have a subtype");
+            (mime.type_() == mime::STAR || mime.type_() == type_)
+                && (mime.subtype() == mime::STAR || mime.subtype() == subtype)
+                && !refused.iter().any(|refused| refused.essence_str() == *known)
+        })
+        .map(|(_, format)| *format)
+}
+
+/// Uses the most preferred known format of the `Accept` header when `output_format` is
+/// not given, responding 406 Not Acceptable when it lists no format that can be output.
+fn output_format_from_accept(req: &HttpRequest, query: &mut Query) -> Result<(), HttpResponse<BoxBody>> {
+    if query.output_format.is_some() {
+        return Ok(());
+    }
+    let accept = match Accept::parse(req) {
+        Ok(accept) if !accept.is_empty() => accept,
+        _ => return Ok(()),
+    };
+    // `q=0` marks a type as not acceptable.
+    let refused: Vec<&mime::Mime> = accept.iter().filter(|item| item.quality == Quality::ZERO).map(|item| &item.item).collect();
+    let format = accept
+        .ranked()
+        .iter()
+        .filter(|mime| !refused.contains(mime))
+        .find_map(|mime| format_for_mime(mime, &refused));
+    match format {
+        Some(format) => {
+            query.output_format = Some(format.to_string());
+            Ok(())
+        }
+        None => {
+            let known: Vec<&str> = ACCEPT_FORMATS.iter().map(|(known, _)| *known).collect();
+            let error = format!("no output format matches `Accept`, accept one of {} or set `output_format`", known.join(", "));
+            log::warn!(error = error; "not acceptable");
+            Err(HttpResponse::NotAcceptable().body(json!({"error": error}).to_string()))
+        }
+    }
+}
+
+async fn convert(config: web::Data<Config>, jobs: web::Data<JobStore>, query: web::Query<Query>, req: HttpRequest) -> Either<HttpResponse<BoxBody>, impl Responder> {
+    let mut query = query.into_inner();
+    if let Err(response) = output_format_from_accept(&req, &mut query) {
+        return Either::Left(response);
+    }
+    process(config, jobs, query, None).await
+}
+
+/// Takes the input as a multipart form, or as a raw JSON, JSON lines or gzip body. JSON
+/// lines bodies are flattened as JSON lines unless `json_lines` says otherwise, and bodies
+/// sent with `Content-Encoding: gzip` are decompressed. Form encoded bodies are taken as raw
+/// JSON, as that is what a raw body sent without a content type is labelled as by curl.
+async fn get_input(config: web::Data<Config>, jobs: web::Data<JobStore>, query: web::Query<Query>, req: HttpRequest, payload: web::Payload) -> Either<HttpResponse<BoxBody>, impl Responder> {
+    let mut query = query.into_inner();
+    if let Err(response) = output_format_from_accept(&req, &mut query) {
+        return Either::Left(response);
+    }
+    let content_type = req
+        .headers()
+        .get(actix_web::http::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .map(|value| value.trim().to_ascii_lowercase())
+        .unwrap_or_default();
+    let gzip_encoded = req
+        .headers()
+        .get(actix_web::http::header::CONTENT_ENCODING)
+        .is_some_and(|value| value.as_bytes().eq_ignore_ascii_case(b"gzip"));
+
+    let upload = match content_type.as_str() {
+        "" => None,
+        "multipart/form-data" => match MultipartForm::<UploadForm>::from_request(&req, &mut payload.into_inner()).await {
+            Ok(MultipartForm(form)) => Some(Upload::Form(Box::new(form))),
+            Err(e) => return Either::Left(bad_request_json(json!({"error": e.to_string()}))),
+        },
+        "application/json" | "application/x-www-form-urlencoded" => Some(Upload::Body { payload, gzip: gzip_encoded }),
+        "application/x-ndjson" => {
+            query.json_lines.get_or_insert(true);
+            Some(Upload::Body { payload, gzip: gzip_encoded })
+        }
+        "application/gzip" | "application/x-gzip" => Some(Upload::Body { payload, gzip: true }),
+        other => {
+            let error = format!("unsupported content type `{}`, use multipart/form-data, application/json, application/x-ndjson or application/gzip", other);
+            log::warn!(error = error; "unsupported media type");
+            return Either::Left(HttpResponse::UnsupportedMediaType().body(json!({"error": error}).to_string()));
+        }
+    };
+    process(config, jobs, query, upload).await
+}
+
+/// Fails writes that would take the total written over `max_size` bytes.
+struct LimitedWriter<W> {
+    inner: W,
+    written: u64,
+    max_size: u64,
+}
+
+impl<W: Write> Write for LimitedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.written + buf.len() as u64 > self.max_size {
+            return Err(std::io::Error::other(format!("Upload Size Exceeded, maximum allowed is {} bytes", self.max_size)));
+        }
+        let written = self.inner.write(buf)?;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
 }
 
-async fn get_input(query: web::Query<Query>, MultipartForm(form): MultipartForm<UploadForm>) -> Either<HttpResponse<BoxBody>, impl Responder> {
-    process(query, Some(form)).await
+/// Writes the chunks of a raw request body to `path`, decompressing them first when `gzip`
+/// is set. The size limit applies to the decompressed input.
+fn write_body(path: &Path, gzip: bool, max_size: u64, chunks: impl Iterator<Item = web::Bytes>) -> std::io::Result<u64> {
+    let writer = LimitedWriter { inner: File::create(path)?, written: 0, max_size };
+
+    let mut writer = if gzip {
+        let mut decoder = flate2::write::GzDecoder::new(writer);
+        for chunk in chunks {
+            decoder.write_all(&chunk)?;
+        }
+        decoder.finish()?
+    } else {
+        let mut writer = writer;
+        for chunk in chunks {
+            writer.write_all(&chunk)?;
+        }
+        writer
+    };
+    writer.flush()?;
+    Ok(writer.written)
+}
+
+/// Streams a raw request body to `download.json`, with the writing and decompressing done
+/// on another thread. Nothing is left behind if the body is too large or cannot be read.
+async fn save_body(mut payload: web::Payload, gzip: bool, tmp_dir: &Path, max_size: u64) -> eyre::Result<u64> {
+    let path = tmp_dir.join("download.json");
+    let (mut sender, receiver) = futures::channel::mpsc::channel::<web::Bytes>(16);
+
+    let receive = async move {
+        while let Some(chunk) = payload.next().await {
+            // The writer has stopped, its error is the one returned.
+            if sender.send(chunk?).await.is_err() {
+                break;
+            }
+        }
+        Ok::<_, eyre::Report>(())
+    };
+    let write_path = path.clone();
+    let write = run_in_thread(move || write_body(&write_path, gzip, max_size, futures::executor::block_on_stream(receiver)));
+
+    let result = match futures::join!(receive, write) {
+        (_, Err(error)) => Err(error.into()),
+        (Err(error), _) => Err(error),
+        (Ok(()), Ok(bytes)) => Ok(bytes),
+    };
+    if result.is_err() {
+        if let Err(e) = std::fs::remove_file(&path) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                log::warn!(error = logging::error_chain(&e); "could not remove partial upload");
+            }
+        }
+    }
+    result
 }
 
 async fn wasm() -> impl Responder {
-    return HttpResponse::Ok().body(json!({"wasm": false}).to_string());
+    HttpResponse::Ok().body(json!({"wasm": false}).to_string())
 }
 
-async fn process(query: web::Query<Query>, upload_form: Option<UploadForm>) -> Either<HttpResponse<BoxBody>, impl Responder> {
-    let tmp_dir = TempDir::new();
-    if let Err(e) = tmp_dir {
-        return Either::Left(internal_error_json(format!("Error creating temp dir: {:?}", e)));
-    } 
-    let tmp_dir_path = tmp_dir.expect("just checked").path().to_owned();
+async fn process(config: web::Data<Config>, jobs: web::Data<JobStore>, mut query: Query, upload: Option<Upload>) -> Either<HttpResponse<BoxBody>, impl Responder> {
+    if !jobs.accepting() {
+        return Either::Left(unavailable_json("server is shutting down, try again shortly"));
+    }
+
+    // Kept until the response file has been opened, so the output is removed afterwards.
+    let output_tmp_dir = match TempDir::new() {
+        Ok(output_tmp_dir) => output_tmp_dir,
+        Err(e) => return Either::Left(internal_error_json(format!("Error creating temp dir: {:?}", e))),
+    };
+    let tmp_dir_path = output_tmp_dir.path().to_owned();
 
     let output_path = tmp_dir_path.join("output");
 
     let mut json_output;
+    let mut uploaded_files = vec![];
 
     if let Some(id) = &query.id {
+        logging::set_job_id(id);
         json_output = json!({ "id": id });
     } else {  
-        let mut uploaded_files = vec![];
-        let clean_tmp_result = clean_tmp();
+        let clean_tmp_result = clean_tmp(&config);
         if let Err(e) = clean_tmp_result {
             return Either::Left(internal_error_json(format!("Error cleaning tmp dir: {:?}", e)));
         }
         let uuid = Uuid::new_v4().hyphenated();
-        let tmp_dir = std::env::temp_dir().join(format!("flatterer-{}", uuid));
+        logging::set_job_id(&uuid.to_string());
+        let tmp_dir = config.job_dir(&uuid.to_string());
         json_output = json!({ "id": uuid.to_string() });
         let create_dir_result = std::fs::create_dir(&tmp_dir);
         if let Err(e) = create_dir_result {
             return Either::Left(internal_error_json(format!("Error creating tmp dir: {:?}", e)));
         }
 
+        let (upload_form, upload_body) = match upload {
+            Some(Upload::Form(form)) => (Some(*form), None),
+            Some(Upload::Body { payload, gzip }) => (None, Some((payload, gzip))),
+            None => (None, None),
+        };
+
+        if let Some((payload, gzip)) = upload_body {
+            match save_body(payload, gzip, &tmp_dir, config.max_size_bytes().min(UPLOAD_PART_LIMIT)).await {
+                Ok(bytes) => {
+                    log::info!(part = "body", gzip = gzip, bytes = bytes; "upload persisted");
+                    if let Err(e) = integrity::record_input(&tmp_dir, None, None) {
+                        return Either::Left(internal_error_json(format!("Error reading file: {:?}", e)));
+                    }
+                }
+                Err(error) => {
+                    log::error!(error = logging::error_chain(error.as_ref()); "upload failed");
+                    json_output = json!({"error": error.to_string()})
+                }
+            }
+            uploaded_files.push("file".to_string());
+        }
+
         if let Some(form) = upload_form {
-            if form.file.is_some() {
-                let file = form.file.unwrap();
+            if let Some(file) = form.file {
                 let file_parsist_result = file.file.persist(tmp_dir.join("download.json"));
                 if let Err(e) = file_parsist_result {
                     return Either::Left(internal_error_json(format!("Error persisting file: {:?}", e)));
                 }
+                if let Err(e) = integrity::record_input(&tmp_dir, file.file_name.as_deref(), None) {
+                    return Either::Left(internal_error_json(format!("Error reading file: {:?}", e)));
+                }
+                log::info!(part = "file", bytes = file.size as u64; "upload persisted");
                 uploaded_files.push("file".to_string());
             }
 
-            if form.fields.is_some() {
-                let fields = form.fields.unwrap();
+            if let Some(fields) = form.fields {
                 let file_parsist_result = fields.file.persist(tmp_dir.join("fields.csv"));
                 if let Err(e) = file_parsist_result {
                     return Either::Left(internal_error_json(format!("Error persisting file: {:?}", e)));
                 }
+                log::info!(part = "fields", bytes = fields.size as u64; "upload persisted");
                 uploaded_files.push("fields".to_string());
             }
 
-            if form.tables.is_some() {
-                let tables = form.tables.unwrap();
+            if let Some(tables) = form.tables {
                 let file_parsist_result = tables.file.persist(tmp_dir.join("tables.csv"));
                 if let Err(e) = file_parsist_result {
                     return Either::Left(internal_error_json(format!("Error persisting file: {:?}", e)));
                 }
+                log::info!(part = "tables", bytes = tables.size as u64; "upload persisted");
                 uploaded_files.push("tables".to_string());
             }
 
-            if let Some(file_url) = &query.file_url {
-                if let Err(error) = download(file_url.clone(), tmp_dir).await {
+            if let Some(schema) = form.schema {
+                let schema_path = tmp_dir.join("schema.json");
+                let file_parsist_result = schema.file.persist(&schema_path);
+                if let Err(e) = file_parsist_result {
+                    return Either::Left(internal_error_json(format!("Error persisting file: {:?}", e)));
+                }
+                if let Err(error) = validate_schema(&schema_path) {
+                    let _ = std::fs::remove_file(&schema_path);
+                    return Either::Left(bad_request_json(json!({"id": uuid.to_string(), "error": error})));
+                }
+                log::info!(part = "schema", bytes = schema.size as u64; "upload persisted");
+                uploaded_files.push("schema".to_string());
+            }
+        }
+
+        if let Some(file_url) = &query.file_url {
+            let url_file_name = url_file_name(file_url);
+            match download(file_url.clone(), tmp_dir.clone(), config.max_size_bytes()).await {
+                Ok(()) => {
+                    if let Err(e) = integrity::record_input(&tmp_dir, url_file_name.as_deref(), None) {
+                        return Either::Left(internal_error_json(format!("Error reading file: {:?}", e)));
+                    }
+                }
+                Err(error) => {
+                    log::error!(error = logging::error_chain(error.as_ref()); "download failed");
                     json_output = json!({"error": error.to_string()})
                 }
-                uploaded_files.push("file".to_string());
             }
+            uploaded_files.push("file".to_string());
         }
 
         if !uploaded_files.contains(&"file".to_string()) {
@@ -310,16 +895,15 @@ async fn process(query: web::Query<Query>, upload_form: Option<UploadForm>) -> E
         }
     }
 
-    let mut download_path = std::env::temp_dir();
-    let mut download_file = std::env::temp_dir();
+    let mut download_path = config.jobs_dir.clone();
+    let mut download_file = config.jobs_dir.clone();
     let mut id = "".to_string();
 
     if let Some(id_value) = json_output.get("id") {
         if let Some(id_string) = id_value.as_str() {
             id = id_string.to_string();
-            download_path.push(format!("flatterer-{}", id_string));
-            download_file.push(format!("flatterer-{}", id_string));
-            download_file.push("download.json");
+            download_path = config.job_dir(id_string);
+            download_file = download_path.join("download.json");
             if !download_file.exists() {
                 json_output = json!({"error": "id does not exist, you may need to ask you file to be downloaded again or to upload the file again."})
             }
@@ -331,136 +915,669 @@ async fn process(query: web::Query<Query>, upload_form: Option<UploadForm>) -> E
         return Either::Left(bad_request_json(json_output))
     }
 
-    let file_result = File::open(download_file);
-    if file_result.is_err() {
-        return Either::Left(internal_error_json(format!("Error opening file: {:?}", file_result.err().unwrap())));
+    if let Some(preset) = query.preset.clone() {
+        let keep: Vec<&str> = [("fields", "fields.csv"), ("tables", "tables.csv")]
+            .into_iter()
+            .filter(|(part, _)| uploaded_files.contains(&part.to_string()))
+            .map(|(_, file_name)| file_name)
+            .collect();
+        if let Err(err) = presets::apply(&config.presets_dir, &preset, &mut query, &keep) {
+            return Either::Left(bad_request_json(json!({"id": id, "error": err})));
+        }
     }
-    let mut file = file_result.unwrap();
 
-    let mut buf = vec![0;10240];
-    let read_result = file.read(&mut buf);
-    if read_result.is_err() {
-        return Either::Left(internal_error_json(format!("Error reading file: {:?}", read_result.err().unwrap())));
+    let start = match read_start(&download_file) {
+        Ok(start) => start,
+        Err(e) => return Either::Left(internal_error_json(format!("Error reading file: {:?}", e))),
+    };
+
+    let (path, json_lines, guess_text) = match guess_input_shape(&query, &start) {
+        Ok(shape) => shape,
+        Err(err) => {
+            let output = json!({"id": id, "error": err, "start": start});
+            return Either::Left(bad_request_json(output))
+        }
+    };
+
+    let output_format = query.output_format.clone().unwrap_or_else(|| "zip".to_string());
+
+    if output_format == "preview" {
+        let mut response = job_preview(&config, &jobs, &id, &query, start, json_lines, path, guess_text).await;
+        if let Ok(value) = HeaderValue::from_str(&id) {
+            response.headers_mut().insert(HeaderName::from_static(JOB_ID_HEADER), value);
+        }
+        return Either::Left(response);
     }
-    let n = read_result.unwrap();
-    let start = String::from_utf8_lossy(&buf[..n]);        
 
-    let mut path = "".to_string();
+    if query.wants_duckdb() && !duckdb_output::AVAILABLE {
+        return Either::Left(bad_request_json(json!({"id": id, "error": duckdb_output::UNAVAILABLE})));
+    }
 
-    if let Some(array_key) = &query.array_key {
-        path = array_key.to_owned();
+    let destination = if output_format == "postgres" {
+        match destination::Destination::from_query(&config, &query) {
+            Ok(destination) => Some(destination),
+            Err(err) => return Either::Left(bad_request_json(json!({"id": id, "error": err}))),
+        }
+    } else {
+        None
     };
 
-    let mut json_lines = query.json_lines.unwrap_or(false);
+    let output_path_copy = output_path.clone();
+    let query_copy = query.clone();
 
-    let mut guess_text = "".to_string();
+    let job = match jobs.start(&id, &download_path, &tmp_dir_path) {
+        Some(job) => job,
+        None => return Either::Left(unavailable_json("server is shutting down, try again shortly")),
+    };
 
-    if path.is_empty() && !json_lines {
-        match libflatterer::guess_array(&start) {
-            Ok((guess, _)) => {
-                if guess == "stream" {
-                    json_lines = true;
-                    guess_text = "JSON Stream".to_string()
-                };
-            }
-            Err(err) => {
-                let output = json!({"id": id, "error": err.to_string(), "start": start});
-                return Either::Left(bad_request_json(output))
-            }
+    let mut flatten_result = run_flatterer_in_thread(query_copy, download_path.clone(), output_path_copy, json_lines, path)
+        .await
+        .map(|_| None);
+    if let (Ok(_), Some(destination)) = (&flatten_result, destination) {
+        let push_path = output_path.clone();
+        flatten_result = run_in_thread(move || destination::push(&destination, &push_path)).await.map(Some);
+    }
+    job.finish(flatten_result.as_ref().err().map(|err| err.to_string()).as_deref());
+
+    let pushed = match flatten_result {
+        Ok(pushed) => pushed,
+        Err(err) => {
+            log::error!(error = logging::error_chain(&err); "flatten failed");
+            let output = json!({"id": id, "error": err.to_string(), "start": start});
+            return Either::Left(bad_request_json(output))
         }
+    };
+    if let Some(mut pushed) = pushed {
+        pushed["id"] = json!(id);
+        return Either::Left(HttpResponse::Ok().insert_header((JOB_ID_HEADER, id)).body(pushed.to_string()));
     }
 
-    let output_path_copy = output_path.clone();
-    let query_copy = query.clone().into_inner();
+    match output_file(&query, &output_path, &tmp_dir_path) {
+        Ok(file) => {
+            let file_name = download_filename(&query, &download_path);
+            let named_file = actix_files::NamedFile::open_async(file).await.map(|named_file| {
+                named_file
+                    .set_content_type(output_mime(&query))
+                    .set_content_disposition(ContentDisposition {
+                        disposition: DispositionType::Attachment,
+                        parameters: vec![DispositionParam::Filename(file_name)],
+                    })
+            });
+            Either::Right(named_file.customize().insert_header((JOB_ID_HEADER, id)))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Either::Left(not_found_json(&e.to_string())),
+        Err(e) => Either::Left(internal_error_json(format!("Error zipping output: {:?}", e))),
+    }
+}
+
+/// The preview JSON for a job, flattening its input first if needed.
+#[allow(clippy::too_many_arguments)]
+async fn job_preview(config: &Config, jobs: &Arc<JobStore>, id: &str, query: &Query, start: String, json_lines: bool, path: String, guess_text: String) -> HttpResponse<BoxBody> {
+    let window = match query.row_window() {
+        Ok(window) => window,
+        Err(err) => return bad_request_json(json!({"id": id, "error": err})),
+    };
+
+    let flattened_path = match flattened_output(config, jobs, id, query, json_lines, path, window.offset.saturating_add(window.rows)).await {
+        Ok(flattened_path) => flattened_path,
+        Err(err) => {
+            log::error!(error = logging::error_chain(&err); "flatten failed");
+            let output = json!({"id": id, "error": err.to_string(), "start": start});
+            return bad_request_json(output)
+        }
+    };
 
-    if let Err(err) = run_flatterer(query_copy, download_path, output_path_copy, json_lines, path) {
-        let output = json!({"id": id, "error": err.to_string(), "start": start});
-        return Either::Left(bad_request_json(output))
+    let fields_value_result = fields_output(flattened_path.clone());
+    if let Err(e) = fields_value_result {
+        return internal_error_json(format!("Error reading fields.csv: {:?}", e));
     }
+    let fields_value = fields_value_result.unwrap();
 
-    let tmp_dir_path_to_move = tmp_dir_path.to_path_buf();
+    let validation = validation::read_summary(&flattened_path);
+    let manifest = integrity::read_manifest(&flattened_path);
+    let relationships = match relationships::read(&flattened_path) {
+        Ok(relationships) => relationships,
+        Err(e) => return internal_error_json(format!("Error reading relationships: {:?}", e)),
+    };
+    let overflows = match xlsx::find_overflows(&flattened_path, xlsx::EXCEL_MAX_ROWS) {
+        Ok(overflows) => overflows,
+        Err(e) => return internal_error_json(format!("Error reading fields.csv: {:?}", e)),
+    };
 
-    let output_format = query.output_format.clone().unwrap_or_else(|| "zip".to_string());
+    let preview_value_result = preview_output(flattened_path, fields_value, window).await;
+    if let Err(e) = preview_value_result {
+        return internal_error_json(format!("Error creating preview: {:?}", e));
+    }
 
-    if output_format == "fields" {
-        return Either::Right(actix_files::NamedFile::open_async(output_path.join("fields.csv")).await);
+    let preview_value = preview_value_result.expect("just checked");
+    let mut output = json!({
+        "id": id,
+        "preview": preview_value,
+        "relationships": relationships,
+        "start": start,
+        "guess_text": guess_text,
+    });
+    if let Some(validation) = validation {
+        output["validation"] = validation;
+    }
+    if let Some(manifest) = manifest {
+        output["manifest"] = manifest;
     }
+    if !overflows.is_empty() {
+        output["warnings"] = json!([xlsx::overflow_warning(&overflows, xlsx::EXCEL_MAX_ROWS)]);
+    }
+
+    HttpResponse::Ok().body(output.to_string())
+}
 
-    if output_format == "tables" {
-        return Either::Right(actix_files::NamedFile::open_async(output_path.join("tables.csv")).await);
+/// Flattens the input of a job to CSV inside the job directory, reusing an earlier output
+/// made with the same options. Only the first `PREVIEW_OUTPUT_ROWS` rows of each table are
+/// kept unless rows up to `rows_needed` go beyond them, when the whole input is output.
+async fn flattened_output(config: &Config, jobs: &Arc<JobStore>, id: &str, query: &Query, json_lines: bool, path: String, rows_needed: usize) -> std::io::Result<PathBuf> {
+    let job_path = config.job_dir(id);
+    let kind = if rows_needed <= PREVIEW_OUTPUT_ROWS { "preview" } else { "full" };
+
+    let mut hasher = DefaultHasher::new();
+    query.flatten_key().hash(&mut hasher);
+    json_lines.hash(&mut hasher);
+    path.hash(&mut hasher);
+    for metadata_file in query.metadata_files(&job_path) {
+        if let Ok(modified) = metadata_file.metadata().and_then(|m| m.modified()) {
+            metadata_file.hash(&mut hasher);
+            modified.hash(&mut hasher);
+        }
     }
+    let dir_name = format!("{}-{:x}", kind, hasher.finish());
+    let flattened_path = job_path.join(&dir_name);
 
-    if output_format == "preview" {
-        let fields_value_result = fields_output(output_path.clone());
-        if let Err(e) = fields_value_result {
-            return Either::Left(internal_error_json(format!("Error reading fields.csv: {:?}", e)));
+    if flattened_path.exists() {
+        return Ok(flattened_path);
+    }
+
+    let tmp_dir = tempfile::Builder::new().prefix(&format!("tmp-{}-", kind)).tempdir_in(&job_path)?;
+    let output_path = tmp_dir.path().join("output");
+
+    let job = jobs
+        .start(id, &job_path, tmp_dir.path())
+        .ok_or_else(|| std::io::Error::other("server is shutting down, try again shortly"))?;
+
+    let mut flatten_query = query.clone();
+    flatten_query.output_format = Some(if kind == "preview" { "preview" } else { "csv" }.to_string());
+    let flatten_result = run_flatterer_in_thread(flatten_query, job_path.clone(), output_path.clone(), json_lines, path).await;
+    job.finish(flatten_result.as_ref().err().map(|err| err.to_string()).as_deref());
+    flatten_result?;
+
+    // Another request may have made the same output in the meantime, which is fine to use.
+    if let Err(err) = std::fs::rename(&output_path, &flattened_path) {
+        if !flattened_path.exists() {
+            return Err(err);
         }
-        let fields_value = fields_value_result.unwrap();
+    }
 
-        let preview_value_result = preview_output(output_path.clone(), fields_value).await;
-        if let Err(e) = preview_value_result {
-            return Either::Left(internal_error_json(format!("Error creating preview: {:?}", e)));
+    // Only the latest output of each kind is kept, earlier options are unlikely to be asked for again.
+    for entry in std::fs::read_dir(&job_path)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name != dir_name && name.starts_with(&format!("{}-", kind)) {
+            if let Err(err) = std::fs::remove_dir_all(entry.path()) {
+                log::warn!(dir = name.as_str(), error = logging::error_chain(&err); "could not remove old output");
+            }
         }
+    }
+    Ok(flattened_path)
+}
 
-        let preview_value = preview_value_result.expect("just checked");
-        let output = json!({"id": id, "preview": preview_value, "start": start, "guess_text": guess_text});
+/// Finds a table by name or title in `tables.csv`, returning both.
+fn find_table(output_path: &Path, table: &str) -> csv::Result<Option<(String, String)>> {
+    let mut tables_reader = Reader::from_path(output_path.join("tables.csv"))?;
+    for row in tables_reader.deserialize() {
+        let table_row: HashMap<String, String> = row?;
+        let table_name = table_row.get("table_name").cloned().unwrap_or_default();
+        let table_title = table_row.get("table_title").cloned().unwrap_or_default();
+        if table == table_name || table == table_title {
+            return Ok(Some((table_name, table_title)));
+        }
+    }
+    Ok(None)
+}
 
-        return Either::Left(HttpResponse::Ok().body(output.to_string()));
+async fn job_table_rows(config: web::Data<Config>, jobs: web::Data<JobStore>, params: web::Path<(String, String)>, query: web::Query<Query>) -> HttpResponse<BoxBody> {
+    let (id, table) = params.into_inner();
 
+    let download_file = config.job_dir(&id).join("download.json");
+    if Uuid::parse_str(&id).is_err() || !download_file.exists() {
+        return not_found_json("id does not exist, you may need to ask you file to be downloaded again or to upload the file again.");
     }
+    logging::set_job_id(&id);
 
-    if output_format == "xlsx" {
-        return Either::Right(actix_files::NamedFile::open_async(output_path.join("output.xlsx")).await);
+    let mut query = query.into_inner();
+    if let Some(preset) = query.preset.clone() {
+        if let Err(err) = presets::apply(&config.presets_dir, &preset, &mut query, &[]) {
+            return bad_request_json(json!({"id": id, "error": err}));
+        }
     }
-    
-    if output_format == "sqlite" {
-        return Either::Right(actix_files::NamedFile::open_async(output_path.join("sqlite.db")).await);
+
+    let window = match query.row_window() {
+        Ok(window) => window,
+        Err(err) => return bad_request_json(json!({"id": id, "error": err})),
+    };
+
+    let start = match read_start(&download_file) {
+        Ok(start) => start,
+        Err(e) => return internal_error_json(format!("Error reading file: {:?}", e)),
+    };
+    let (path, json_lines, _) = match guess_input_shape(&query, &start) {
+        Ok(shape) => shape,
+        Err(err) => return bad_request_json(json!({"id": id, "error": err, "start": start})),
+    };
+
+    let flattened_path = match flattened_output(&config, &jobs, &id, &query, json_lines, path, window.offset.saturating_add(window.rows)).await {
+        Ok(flattened_path) => flattened_path,
+        Err(err) => {
+            log::error!(error = logging::error_chain(&err); "flatten failed");
+            return bad_request_json(json!({"id": id, "error": err.to_string(), "start": start}));
+        }
+    };
+
+    let (table_name, table_title) = match find_table(&flattened_path, &table) {
+        Ok(Some(found)) => found,
+        Ok(None) => return not_found_json(&format!("table `{}` does not exist", table)),
+        Err(e) => return internal_error_json(format!("Error reading tables.csv: {:?}", e)),
+    };
+
+    let fields: Vec<HashMap<String, String>> = match fields_output(flattened_path.clone()) {
+        Ok(fields) => fields
+            .into_iter()
+            .filter(|field| field.get("table_name") == Some(&table_name))
+            .collect(),
+        Err(e) => return internal_error_json(format!("Error reading fields.csv: {:?}", e)),
+    };
+
+    let table_path = flattened_path.join("csv").join(format!("{}.csv", table_title));
+    let row_count = row_count(&fields);
+    let rows = match table_rows(&table_path, window.offset, window.rows) {
+        Ok(rows) => rows,
+        Err(e) => return internal_error_json(format!("Error reading table: {:?}", e)),
+    };
+
+    let output = json!({
+        "id": id,
+        "table_name": table_title,
+        "columns": typed::columns(&fields),
+        "rows": typed::typed_rows(&rows, &fields),
+        "offset": window.offset,
+        "row_count": row_count,
+    });
+    HttpResponse::Ok().body(output.to_string())
+}
+
+fn default_include() -> bool {
+    true
+}
+
+/// A field as edited from the preview, listed in the order it should be output.
+#[derive(Deserialize, Debug)]
+struct FieldEdit {
+    table_name: String,
+    field_name: String,
+    field_title: Option<String>,
+    field_type: Option<String>,
+    /// Fields not included are left out of `fields.csv`, so are dropped when `fields_only` is set.
+    #[serde(default = "default_include")]
+    include: bool,
+}
+
+/// A table as edited from the preview, listed in the order it should be output.
+#[derive(Deserialize, Debug)]
+struct TableEdit {
+    table_name: String,
+    table_title: Option<String>,
+    /// Tables not included are left out of `tables.csv`, so are dropped when `tables_only` is set.
+    #[serde(default = "default_include")]
+    include: bool,
+}
+
+/// Writes `rows` as a CSV to `path`, replacing it in one step so a preview never reads a
+/// partly written file.
+fn replace_csv(path: &Path, header: &[&str], rows: Vec<Vec<String>>) -> std::io::Result<()> {
+    let dir = path.parent().expect("job files are in a directory");
+    let tmp_file = tempfile::NamedTempFile::new_in(dir)?;
+    let mut writer = csv::Writer::from_writer(tmp_file.as_file());
+    writer.write_record(header)?;
+    for row in rows {
+        writer.write_record(row)?;
     }
+    writer.flush()?;
+    drop(writer);
+    tmp_file.persist(path).map_err(|e| e.error)?;
+    Ok(())
+}
 
-    if output_format == "csv" {
-        let main_table_name = query.main_table_name.clone().unwrap_or_else(|| "main".to_string());
-        return Either::Right(actix_files::NamedFile::open_async(output_path.join("csv").join(format!("{}.csv", main_table_name))).await);
+fn write_fields_csv(path: &Path, fields: &[FieldEdit]) -> Result<(), String> {
+    let mut seen = std::collections::HashSet::new();
+    let mut rows = vec![];
+    for field in fields {
+        if field.table_name.is_empty() || field.field_name.is_empty() {
+            return Err("every field needs a `table_name` and `field_name`".to_string());
+        }
+        if !seen.insert((&field.table_name, &field.field_name)) {
+            return Err(format!("field `{}` of table `{}` is listed more than once", field.field_name, field.table_name));
+        }
+        if !field.include {
+            continue;
+        }
+        rows.push(vec![
+            field.table_name.clone(),
+            field.field_name.clone(),
+            field.field_type.clone().unwrap_or_default(),
+            field.field_title.clone().filter(|title| !title.is_empty()).unwrap_or_else(|| field.field_name.clone()),
+        ]);
+    }
+    if rows.is_empty() {
+        return Err("at least one field needs to be included".to_string());
     }
+    replace_csv(path, &["table_name", "field_name", "field_type", "field_title"], rows)
+        .map_err(|e| format!("could not write fields.csv: {}", e))
+}
 
-    let zip_output_result = zip_output(output_path.clone(), tmp_dir_path_to_move.to_path_buf());
+fn write_tables_csv(path: &Path, tables: &[TableEdit]) -> Result<(), String> {
+    let mut seen = std::collections::HashSet::new();
+    let mut rows = vec![];
+    for table in tables {
+        if table.table_name.is_empty() {
+            return Err("every table needs a `table_name`".to_string());
+        }
+        if !seen.insert(&table.table_name) {
+            return Err(format!("table `{}` is listed more than once", table.table_name));
+        }
+        if !table.include {
+            continue;
+        }
+        rows.push(vec![
+            table.table_name.clone(),
+            table.table_title.clone().filter(|title| !title.is_empty()).unwrap_or_else(|| table.table_name.clone()),
+        ]);
+    }
+    if rows.is_empty() {
+        return Err("at least one table needs to be included".to_string());
+    }
+    replace_csv(path, &["table_name", "table_title"], rows)
+        .map_err(|e| format!("could not write tables.csv: {}", e))
+}
+
+async fn put_job_fields(config: web::Data<Config>, jobs: web::Data<JobStore>, id: web::Path<String>, query: web::Query<Query>, fields: web::Json<Vec<FieldEdit>>) -> HttpResponse<BoxBody> {
+    edit_job(&config, &jobs, &id, query.into_inner(), "fields.csv", |path| write_fields_csv(path, &fields)).await
+}
+
+async fn put_job_tables(config: web::Data<Config>, jobs: web::Data<JobStore>, id: web::Path<String>, query: web::Query<Query>, tables: web::Json<Vec<TableEdit>>) -> HttpResponse<BoxBody> {
+    edit_job(&config, &jobs, &id, query.into_inner(), "tables.csv", |path| write_tables_csv(path, &tables)).await
+}
+
+/// Puts back a job file as it was before an edit, removing it if there was none.
+fn restore_file(path: &Path, previous: Option<Vec<u8>>) -> std::io::Result<()> {
+    match previous {
+        Some(contents) => {
+            let tmp_file = tempfile::NamedTempFile::new_in(path.parent().expect("job files are in a directory"))?;
+            std::fs::write(tmp_file.path(), contents)?;
+            tmp_file.persist(path).map_err(|e| e.error)?;
+            Ok(())
+        }
+        None => std::fs::remove_file(path),
+    }
+}
+
+/// Applies `edit` to the job's `file_name`, then returns the preview made with the edited
+/// files. The file is put back as it was if the preview fails, so a bad edit does not break
+/// the job. Only the options of a `preset` are used, so its files do not replace the edit.
+async fn edit_job(config: &Config, jobs: &Arc<JobStore>, id: &str, mut query: Query, file_name: &str, edit: impl FnOnce(&Path) -> Result<(), String>) -> HttpResponse<BoxBody> {
+    let job_path = config.job_dir(id);
+    let download_file = job_path.join("download.json");
+    if Uuid::parse_str(id).is_err() || !download_file.exists() {
+        return not_found_json("id does not exist, you may need to ask you file to be downloaded again or to upload the file again.");
+    }
+    logging::set_job_id(id);
+
+    if let Some(preset) = query.preset.clone() {
+        if let Err(err) = presets::apply(&config.presets_dir, &preset, &mut query, &["fields.csv", "tables.csv"]) {
+            return bad_request_json(json!({"id": id, "error": err}));
+        }
+    }
+
+    let start = match read_start(&download_file) {
+        Ok(start) => start,
+        Err(e) => return internal_error_json(format!("Error reading file: {:?}", e)),
+    };
+    let (path, json_lines, guess_text) = match guess_input_shape(&query, &start) {
+        Ok(shape) => shape,
+        Err(err) => return bad_request_json(json!({"id": id, "error": err, "start": start})),
+    };
+
+    let file_path = job_path.join(file_name);
+    let previous = match std::fs::read(&file_path) {
+        Ok(contents) => Some(contents),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return internal_error_json(format!("Error reading {}: {:?}", file_name, e)),
+    };
+
+    if let Err(err) = edit(&file_path) {
+        return bad_request_json(json!({"id": id, "error": err}));
+    }
 
-    if let Err(e) = zip_output_result {
-        return Either::Left(internal_error_json(format!("Error zipping output: {:?}", e)));
+    let response = job_preview(config, jobs, id, &query, start, json_lines, path, guess_text).await;
+    if !response.status().is_success() {
+        if let Err(err) = restore_file(&file_path, previous) {
+            log::error!(file = file_name, error = logging::error_chain(&err); "could not restore file after failed edit");
+        }
     }
+    response
+}
 
-    return Either::Right(actix_files::NamedFile::open_async( tmp_dir_path.join("export.zip")).await);
+/// Read the start of the input, used to guess its shape and to show in error messages.
+fn read_start(download_file: &Path) -> std::io::Result<String> {
+    let mut file = File::open(download_file)?;
+    let mut buf = vec![0;10240];
+    let n = file.read(&mut buf)?;
+    Ok(String::from_utf8_lossy(&buf[..n]).to_string())
 }
 
+/// Returns the path to flatten, whether the input is JSON lines and a description of
+/// anything guessed. When neither `array_key` or `json_lines` is given the shape is
+/// guessed from the start of the input.
+fn guess_input_shape(query: &Query, start: &str) -> Result<(String, bool, String), String> {
+    let path = query.array_key.clone().unwrap_or_default();
+    let mut json_lines = query.json_lines.unwrap_or(false);
+    let mut guess_text = "".to_string();
 
-fn clean_tmp() ->  std::io::Result<()> {
+    if path.is_empty() && !json_lines {
+        // The guesser panics on some input that is not JSON.
+        let (guess, _) = std::panic::catch_unwind(|| libflatterer::guess_array(start))
+            .map_err(|_| "input does not look like JSON".to_string())?
+            .map_err(|err| err.to_string())?;
+        if guess == "stream" {
+            json_lines = true;
+            guess_text = "JSON Stream".to_string()
+        };
+    }
+    Ok((path, json_lines, guess_text))
+}
 
-    let clean_tmp_time = if let Ok(clean_tmp_time) = var("CLEAN_TMP_TIME") {
-        match clean_tmp_time.parse::<u64>() {
-            Ok(clean_tmp_time) => {clean_tmp_time},
-            _ => {3600}
+/// The last segment of a URL's path, recorded as the file name of a downloaded input.
+fn url_file_name(url: &str) -> Option<String> {
+    reqwest::Url::parse(url)
+        .ok()
+        .and_then(|url| url.path_segments().and_then(|mut segments| segments.next_back().map(|name| name.to_string())))
+}
+
+/// The name of the uploaded file or downloaded URL, to name the job's outputs after.
+fn source_name(job_path: &Path) -> Option<String> {
+    let contents = std::fs::read_to_string(job_path.join("source.json")).ok()?;
+    let source: Value = serde_json::from_str(&contents).ok()?;
+    source["file_name"].as_str().map(|file_name| file_name.to_string())
+}
+
+/// Reduces a user supplied name to letters, numbers, `.`, `-` and `_`, without any directories.
+fn sanitize_filename(name: &str) -> Option<String> {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let mut sanitized = String::new();
+    for c in name.chars() {
+        let c = if c.is_ascii_alphanumeric() || c == '.' || c == '-' { c } else { '_' };
+        if !(c == '_' && sanitized.ends_with('_')) {
+            sanitized.push(c);
         }
+    }
+    let sanitized: String = sanitized.trim_matches(['.', '_', '-']).chars().take(100).collect();
+    if sanitized.is_empty() {
+        None
     } else {
-        3600
+        Some(sanitized)
+    }
+}
+
+/// The file name a download is served as: `filename` if given, otherwise named after the
+/// input file or URL, or the main table, always with the extension of the format.
+fn download_filename(query: &Query, job_path: &Path) -> String {
+    let output_format = query.output_format.as_deref().unwrap_or("zip");
+    let extension = match output_format {
+        "xlsx" => "xlsx",
+        "sqlite" => "sqlite",
+        "duckdb" => "duckdb",
+        "csv" | "fields" | "tables" => "csv",
+        "diagram" => match query.diagram_format.as_deref().and_then(diagram::format_file) {
+            Some((file_name, _)) => file_name.rsplit('.').next().unwrap_or("zip"),
+            None => "zip",
+        },
+        "json" | "ndjson" if query.table.is_some() => output_format,
+        _ => "zip",
     };
 
+    // Any extension the client gave is replaced, so it always matches what is served.
+    if let Some(file_name) = query.filename.as_deref().and_then(sanitize_filename) {
+        let stem = match file_name.rsplit_once('.') {
+            Some((stem, _)) if !stem.is_empty() => stem,
+            _ => file_name.as_str(),
+        };
+        return format!("{}.{}", stem, extension);
+    }
+
+    let main_table_name = query.main_table_name.clone().filter(|name| !name.is_empty());
+    let source = source_name(job_path).and_then(|name| {
+        let mut stem = name.as_str();
+        for input_extension in [".gz", ".json", ".jsonl", ".ndjson", ".geojson"] {
+            if stem.to_ascii_lowercase().ends_with(input_extension) {
+                stem = &stem[..stem.len() - input_extension.len()];
+            }
+        }
+        sanitize_filename(stem)
+    });
+    let base = source
+        .clone()
+        .or_else(|| main_table_name.as_deref().and_then(sanitize_filename))
+        .unwrap_or_else(|| "flatterer".to_string());
+
+    match output_format {
+        "fields" | "tables" => format!("{}_{}.csv", base, output_format),
+        "datapackage" => format!("{}_datapackage.zip", base),
+        "sql" => format!("{}_sql.zip", base),
+        "diagram" => format!("{}_diagram.{}", base, extension),
+        "json" | "ndjson" => match query.table.as_deref().and_then(sanitize_filename) {
+            Some(table) => format!("{}_{}.{}", base, table, extension),
+            None => format!("{}_{}.zip", base, output_format),
+        },
+        "csv" => {
+            let main_table = main_table_name.as_deref().and_then(sanitize_filename).unwrap_or_else(|| "main".to_string());
+            if source.is_some() {
+                format!("{}_{}.csv", base, main_table)
+            } else {
+                format!("{}.csv", main_table)
+            }
+        }
+        _ => format!("{}.{}", base, extension),
+    }
+}
+
+fn output_mime(query: &Query) -> mime::Mime {
+    match query.output_format.as_deref().unwrap_or("zip") {
+        "xlsx" => XLSX_MIME.parse().expect("valid mime type"),
+        "sqlite" => "application/vnd.sqlite3".parse().expect("valid mime type"),
+        "duckdb" => mime::APPLICATION_OCTET_STREAM,
+        "csv" | "fields" | "tables" => mime::TEXT_CSV_UTF_8,
+        "diagram" => match query.diagram_format.as_deref().and_then(diagram::format_file) {
+            Some((_, diagram_mime)) => diagram_mime.parse().expect("valid mime type"),
+            None => "application/zip".parse().expect("valid mime type"),
+        },
+        "json" if query.table.is_some() => mime::APPLICATION_JSON,
+        "ndjson" if query.table.is_some() => "application/x-ndjson".parse().expect("valid mime type"),
+        _ => "application/zip".parse().expect("valid mime type"),
+    }
+}
+
+/// The file to return for a non preview `output_format`, zipping the output first when needed.
+fn output_file(query: &Query, output_path: &Path, tmp_dir_path: &Path) -> std::io::Result<PathBuf> {
+    let output_format = query.output_format.clone().unwrap_or_else(|| "zip".to_string());
+
+    match output_format.as_str() {
+        "fields" => Ok(output_path.join("fields.csv")),
+        "tables" => Ok(output_path.join("tables.csv")),
+        "xlsx" => Ok(output_path.join(xlsx::XLSX_FILE)),
+        "sqlite" => Ok(output_path.join("sqlite.db")),
+        "duckdb" => Ok(output_path.join(duckdb_output::DUCKDB_FILE)),
+        "datapackage" => {
+            zip_output(output_path.to_path_buf(), tmp_dir_path.to_path_buf())?;
+            Ok(tmp_dir_path.join("export.zip"))
+        }
+        "diagram" => match query.diagram_format.as_deref().and_then(diagram::format_file) {
+            Some((file_name, _)) => Ok(output_path.join(diagram::DIAGRAM_DIR).join(file_name)),
+            None => {
+                zip_output(output_path.join(diagram::DIAGRAM_DIR), tmp_dir_path.to_path_buf())?;
+                Ok(tmp_dir_path.join("export.zip"))
+            }
+        },
+        "json" | "ndjson" => {
+            let (dir, extension) = json_output::format_dir(&output_format).expect("json or ndjson");
+            match &query.table {
+                Some(table) => {
+                    let (_, table_title) = find_table(output_path, table)
+                        .map_err(std::io::Error::other)?
+                        .filter(|(_, table_title)| output_path.join(dir).join(format!("{}.{}", table_title, extension)).exists())
+                        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, format!("no table `{}` in the output", table)))?;
+                    Ok(output_path.join(dir).join(format!("{}.{}", table_title, extension)))
+                }
+                None => {
+                    zip_output(output_path.join(dir), tmp_dir_path.to_path_buf())?;
+                    Ok(tmp_dir_path.join("export.zip"))
+                }
+            }
+        }
+        "csv" => {
+            let main_table_name = query.main_table_name.clone().unwrap_or_else(|| "main".to_string());
+            Ok(output_path.join("csv").join(format!("{}.csv", main_table_name)))
+        }
+        _ => {
+            zip_output(output_path.to_path_buf(), tmp_dir_path.to_path_buf())?;
+            Ok(tmp_dir_path.join("export.zip"))
+        }
+    }
+}
+
 
-    for entry in WalkDir::new("/tmp/")
+fn clean_tmp(config: &Config) ->  std::io::Result<()> {
+
+    for entry in WalkDir::new(&config.jobs_dir)
         .min_depth(1)
+        .max_depth(1)
         .into_iter()
         .filter_map(|e| e.ok())
     {
-        if !entry
-            .file_name()
-            .to_string_lossy()
-            .starts_with("flatterer-")
-        {
+        let file_name = entry.file_name().to_string_lossy();
+        // Conversions run from the command line clean up after themselves.
+        if !file_name.starts_with("flatterer-") || file_name.starts_with(batch::CLI_JOB_PREFIX) {
             continue;
         }
-        if entry.metadata()?.modified()?.elapsed().map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "elapsed time not able to be calculated"))?.as_secs() > clean_tmp_time {
+        if entry.metadata()?.modified()?.elapsed().map_err(|_| std::io::Error::other("elapsed time not able to be calculated"))?.as_secs() > config.clean_tmp_time {
             log::debug!("Removing tmp dir: {:?}", entry);
 
             if entry.metadata()?.is_dir() {
-                std::fs::remove_dir_all(&entry.into_path())?;
+                std::fs::remove_dir_all(entry.into_path())?;
             }
         }
     }
@@ -468,34 +1585,85 @@ fn clean_tmp() ->  std::io::Result<()> {
 }
 
 
-#[actix_web::main]
-pub async fn main() -> std::io::Result<()> {
-    env_logger::init();
-    clean_tmp()?;
-
-    let port = if let Ok(port) = var("PORT") {
-        port
-    } else {
-        "8080".to_string()
-    };
-
-    let host = if let Ok(host) = var("HOST") {
-        host
-    } else {
-        "127.0.0.1".to_string()
-    };
+/// Registers the API and the configuration its requests are read with. The job store and
+/// upload locks are left to the caller, as the server shares them with its shutdown.
+fn api(cfg: &mut web::ServiceConfig, config: web::Data<Config>) {
+    cfg.app_data(MultipartFormConfig::default().total_limit(config.max_size_bytes() as usize))
+        .app_data(config)
+        .app_data(web::JsonConfig::default().error_handler(|err, _| {
+            let response = bad_request_json(json!({"error": err.to_string()}));
+            actix_web::error::InternalError::from_response(err, response).into()
+        }))
+        .service(
+            web::resource("/wasm.json").route(web::get().to(wasm))
+        )
+        .service(
+            web::resource("/api/get_input")
+            .route(web::post().to(get_input))
+            .route(web::get().to(get_input))
+            .route(web::put().to(get_input))
+        )
+        .service(
+            web::resource("/api/convert")
+            .route(web::post().to(convert))
+            .route(web::get().to(convert))
+            .route(web::put().to(convert))
+        )
+        .service(
+            web::resource("/api/jobs/{id}/tables/{table}/rows")
+            .route(web::get().to(job_table_rows))
+        )
+        .service(
+            web::resource("/api/uploads")
+            .route(web::post().to(uploads::create_upload))
+        )
+        .service(
+            web::resource("/api/uploads/{id}")
+            .route(web::get().to(uploads::upload_status))
+            .route(web::put().to(uploads::put_chunk))
+        )
+        .service(
+            web::resource("/api/uploads/{id}/finalize")
+            .route(web::post().to(uploads::finalize_upload))
+        )
+        .service(
+            web::resource("/api/presets")
+            .route(web::get().to(presets::list_presets))
+        )
+        .service(
+            web::resource("/api/presets/{name}")
+            .route(web::get().to(presets::get_preset))
+            .route(web::put().to(presets::put_preset))
+            .route(web::delete().to(presets::delete_preset))
+        )
+        .service(
+            web::resource("/api/jobs/{id}/fields")
+            .route(web::put().to(put_job_fields))
+        )
+        .service(
+            web::resource("/api/jobs/{id}/tables")
+            .route(web::put().to(put_job_tables))
+        );
+}
 
+pub fn main(config: Config) -> eyre::Result<()> {
+    actix_web::rt::System::new().block_on(serve(config))
+}
 
+async fn serve(config: Config) -> eyre::Result<()> {
+    logging::init();
+    std::fs::create_dir_all(&config.jobs_dir)?;
+    clean_tmp(&config)?;
 
-    let open_browser = if let Ok(_) = var("OPEN_BROWSER") {
-        true
-    } else {
-        false
+    let tls_config = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => Some(tls::server_config(cert, key)?),
+        _ => None,
     };
 
-    let path = format!("http://{}:{}", host, port);
+    let scheme = if config.tls_enabled() { "https" } else { "http" };
+    let path = format!("{}://{}:{}", scheme, config.host, config.port);
 
-    if open_browser {
+    if config.open_browser {
         match open::that(&path) {
             Ok(()) => println!("Opened browser '{}' successfully.", path),
             Err(err) => eprintln!("An error occurred when opening browser'{}': {}", path, err),
@@ -504,39 +1672,422 @@ pub async fn main() -> std::io::Result<()> {
         println!("Running at '{path}'.")
     }
 
-    let static_files = if let Ok(static_files) = var("STATIC_FILES") {
-        if let Some(static_files) = static_files.strip_suffix("/") {
-            static_files.to_owned()
-        } else {
-            static_files
-        }
-    } else {
-        "dist".to_owned()
-    };
+    if !config.static_files.is_dir() {
+        log::warn!(static_files = config.static_files.to_string_lossy(); "static files directory does not exist, UI will not be served");
+    }
 
-    let port: u16 = port.parse().expect("PORT must be a valid u16 integer");
+    let host = config.host.clone();
+    let port = config.port;
+    let shutdown_timeout = config.shutdown_timeout;
+    let redirect_http_port = config.redirect_http_port;
+    let config = web::Data::new(config);
+    let jobs = Arc::new(JobStore::default());
+    let jobs_data = web::Data::from(jobs.clone());
+    let upload_locks = web::Data::new(uploads::UploadLocks::default());
 
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         App::new()
-            .wrap(Logger::default())
-            .service(
-                web::resource("/wasm.json").route(web::get().to(wasm))
-            )
-            .service(
-                web::resource("/api/get_input")
-                .route(web::post().to(get_input))
-                .route(web::get().to(get_input))
-                .route(web::put().to(get_input))
-            )
-            .service(
-                web::resource("/api/convert")
-                .route(web::post().to(convert))
-                .route(web::get().to(convert))
-                .route(web::put().to(convert))
-            )
-            .service(Files::new("/", static_files.clone()).index_file("index.html"))
+            .wrap(from_fn(logging::request_context))
+            .app_data(jobs_data.clone())
+            .app_data(upload_locks.clone())
+            .configure(|cfg| api(cfg, config.clone()))
+            .service(Files::new("/", config.static_files.clone()).index_file("index.html"))
     })
-    .bind((host, port))?
-    .run()
-    .await
-}
\ No newline at end of file
+    .disable_signals()
+    .shutdown_timeout(shutdown_timeout);
+
+    let server = match tls_config {
+        Some(tls_config) => server.bind_rustls_0_23((host.clone(), port), tls_config)?,
+        None => server.bind((host.clone(), port))?,
+    }
+    .run();
+
+    let redirect_server = match redirect_http_port {
+        Some(redirect_http_port) => {
+            let redirect_server = HttpServer::new(move || {
+                App::new()
+                    .app_data(web::Data::new(tls::HttpsPort(port)))
+                    .default_service(web::to(tls::redirect_to_https))
+            })
+            .disable_signals()
+            .workers(1)
+            .bind((host, redirect_http_port))?
+            .run();
+            let handle = redirect_server.handle();
+            actix_web::rt::spawn(redirect_server);
+            Some(handle)
+        }
+        None => None,
+    };
+
+    actix_web::rt::spawn(jobs::shutdown_on_signal(
+        server.handle(),
+        jobs.clone(),
+        Duration::from_secs(shutdown_timeout),
+    ));
+
+    server.await?;
+
+    if let Some(redirect_server) = redirect_server {
+        redirect_server.stop(true).await;
+    }
+
+    jobs.fail_unfinished();
+    log::info!("shutdown complete");
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use actix_web::http::header::CONTENT_TYPE;
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+
+    use super::*;
+
+    /// The API with a job store of its own, for `App::configure`. Point `config.jobs_dir` at
+    /// a temporary directory, as conversions clean up the stale jobs in it.
+    pub(crate) fn test_api(config: Config) -> impl FnOnce(&mut web::ServiceConfig) {
+        move |cfg| {
+            cfg.app_data(web::Data::new(JobStore::default()))
+                .app_data(web::Data::new(uploads::UploadLocks::default()));
+            api(cfg, web::Data::new(config));
+        }
+    }
+
+    /// A `multipart/form-data` body with a file for each `(name, contents)` part, and the
+    /// content type to send it with.
+    pub(crate) fn multipart(parts: &[(&str, &[u8])]) -> (String, Vec<u8>) {
+        let boundary = "flatterer-test-boundary";
+        let mut body = vec![];
+        for (name, contents) in parts {
+            body.extend_from_slice(
+                format!(
+                    "--{boundary}\r\nContent-Disposition: form-data; name=\"{name}\"; filename=\"{name}\"\r\nContent-Type: application/octet-stream\r\n\r\n"
+                )
+                .as_bytes(),
+            );
+            body.extend_from_slice(contents);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(format!("--{boundary}--\r\n").as_bytes());
+        (format!("multipart/form-data; boundary={boundary}"), body)
+    }
+
+    /// A request for the preview of `fixture` sent as the request body, which starts a job.
+    pub(crate) fn preview_request(fixture: &str) -> TestRequest {
+        TestRequest::post()
+            .uri("/api/get_input?output_format=preview")
+            .insert_header((CONTENT_TYPE, "application/json"))
+            .set_payload(std::fs::read(Path::new("fixtures").join(fixture)).unwrap())
+    }
+
+    /// The status of a response and its body, as JSON when it is JSON.
+    pub(crate) async fn status_and_body(response: actix_web::dev::ServiceResponse) -> (u16, Value) {
+        let status = response.status().as_u16();
+        let body = read_body(response).await;
+        let body = serde_json::from_slice(&body).unwrap_or_else(|_| json!(format!("{} bytes", body.len())));
+        (status, body)
+    }
+
+    /// Flattens a file from `fixtures` to CSV the way a conversion does, returning the job
+    /// directory with the output in `output`.
+    pub(crate) fn flatten_fixture(fixture: &str) -> tempfile::TempDir {
+        let job_dir = tempfile::tempdir().unwrap();
+        std::fs::copy(Path::new("fixtures").join(fixture), job_dir.path().join("download.json")).unwrap();
+        let query = Query { output_format: Some("csv".to_string()), ..Default::default() };
+        run_flatterer(query, job_dir.path().to_path_buf(), job_dir.path().join("output"), false, String::new()).unwrap();
+        job_dir
+    }
+
+    #[test]
+    fn sanitize_filenames() {
+        let names: Vec<(&str, Option<String>)> = [
+            "games.json",
+            "../../etc/passwd",
+            "C:\\Users\\me\\My Games.json",
+            "spaces and   symbols!?.xlsx",
+            "..",
+            "___",
+            "ünïcode.json",
+        ]
+        .into_iter()
+        .map(|name| (name, sanitize_filename(name)))
+        .collect();
+        insta::assert_yaml_snapshot!(names);
+    }
+
+    #[test]
+    fn download_filenames() {
+        let job_dir = tempfile::tempdir().unwrap();
+        let named_job_dir = tempfile::tempdir().unwrap();
+        std::fs::write(named_job_dir.path().join("source.json"), json!({"file_name": "Games List.json.gz"}).to_string()).unwrap();
+
+        let query = |params: &str| -> Query { web::Query::<Query>::from_query(params).unwrap().into_inner() };
+        let cases = [
+            "",
+            "output_format=xlsx",
+            "output_format=csv",
+            "output_format=csv&main_table_name=games",
+            "output_format=fields",
+            "output_format=sqlite&main_table_name=games",
+            "output_format=json&table=platforms",
+            "output_format=diagram&diagram_format=svg",
+            "output_format=xlsx&filename=report",
+            "output_format=xlsx&filename=report.csv",
+            "output_format=csv&filename=../data.v2.zip",
+            "output_format=sqlite&filename=.hidden",
+        ];
+        let names: Vec<(&str, String, String)> = cases
+            .into_iter()
+            .map(|params| {
+                (params, download_filename(&query(params), job_dir.path()), download_filename(&query(params), named_job_dir.path()))
+            })
+            .collect();
+        insta::assert_yaml_snapshot!(names);
+    }
+
+    #[test]
+    fn table_rows_window() {
+        let job_dir = flatten_fixture("basic.json");
+        let output_path = job_dir.path().join("output");
+        let fields = fields_output(output_path.clone()).unwrap();
+        let platform_fields: Vec<HashMap<String, String>> = fields
+            .into_iter()
+            .filter(|field| field.get("table_name").map(String::as_str) == Some("platforms"))
+            .collect();
+        let csv_path = output_path.join("csv").join("platforms.csv");
+
+        let windows = json!({
+            "row_count": row_count(&platform_fields),
+            "first_two": table_rows(&csv_path, 0, 2).unwrap(),
+            "from_offset": table_rows(&csv_path, 1, 10).unwrap(),
+            "past_end": table_rows(&csv_path, 5, 10).unwrap(),
+        });
+        insta::assert_yaml_snapshot!(windows);
+    }
+
+    #[actix_web::test]
+    async fn schema_uploads() {
+        let jobs_dir = tempfile::tempdir().unwrap();
+        let config = Config { jobs_dir: jobs_dir.path().to_path_buf(), ..Default::default() };
+        let app = init_service(App::new().configure(test_api(config.clone()))).await;
+        let input = std::fs::read("fixtures/basic.json").unwrap();
+
+        let mut responses = vec![];
+        for schema in [&b"[1, 2]"[..], b"\"a schema\"", b"{\"type\": ", br#"{"type": "object", "properties": {"title": {"title": "Title"}}}"#] {
+            let (content_type, body) = multipart(&[("file", &input), ("schema", schema)]);
+            let request = TestRequest::post()
+                .uri("/api/get_input?output_format=fields")
+                .insert_header((CONTENT_TYPE, content_type))
+                .set_payload(body);
+            let (status, mut body) = status_and_body(call_service(&app, request.to_request()).await).await;
+            if let Some(id) = body.get("id").and_then(Value::as_str) {
+                // A rejected schema is not kept for later conversions of the job.
+                assert!(!config.job_dir(id).join("schema.json").exists());
+                body["id"] = json!("[id]");
+            }
+            responses.push((String::from_utf8_lossy(schema).to_string(), status, body));
+        }
+        insta::assert_yaml_snapshot!(responses);
+    }
+
+    #[actix_web::test]
+    async fn edit_job_files() {
+        let jobs_dir = tempfile::tempdir().unwrap();
+        let config = Config { jobs_dir: jobs_dir.path().to_path_buf(), ..Default::default() };
+        let app = init_service(App::new().configure(test_api(config.clone()))).await;
+        let (_, preview) = status_and_body(call_service(&app, preview_request("basic.json").to_request()).await).await;
+        let id = preview["id"].as_str().unwrap();
+        let fields_csv = config.job_dir(id).join("fields.csv");
+        let tables_csv = config.job_dir(id).join("tables.csv");
+
+        let fields = json!([
+            {"table_name": "main", "field_name": "title", "field_title": "Game Title"},
+            {"table_name": "main", "field_name": "id"},
+            {"table_name": "main", "field_name": "releaseDate", "include": false},
+        ]);
+        let mut responses = vec![];
+        for (uri, body) in [
+            ("fields", fields.clone()),
+            // Not written, so the fields.csv above is kept.
+            ("fields", json!([{"table_name": "main", "field_name": "id"}, {"table_name": "main", "field_name": "id"}])),
+            ("fields", json!([{"table_name": "main", "field_name": "id", "include": false}])),
+            ("fields", json!([{"table_name": "main"}])),
+            // Written then put back, as the preview fails without a schema to validate against.
+            ("fields?validate=true", json!([{"table_name": "main", "field_name": "id"}])),
+            // Removed again, as there was no tables.csv before.
+            ("tables?validate=true", json!([{"table_name": "main"}])),
+            ("tables", json!([{"table_name": "platforms", "table_title": "Platforms"}, {"table_name": "main"}])),
+            ("tables", json!([{"table_name": ""}])),
+        ] {
+            let request = TestRequest::put().uri(&format!("/api/jobs/{}/{}", id, uri)).set_json(body);
+            let (status, body) = status_and_body(call_service(&app, request.to_request()).await).await;
+            let error = body.get("error").cloned();
+            let main_columns = body.pointer("/preview/0/fields").map(|fields| {
+                fields.as_array().unwrap().iter().map(|field| field["field_title"].clone()).collect::<Vec<_>>()
+            });
+            let tables = body.get("preview").map(|preview| {
+                preview.as_array().unwrap().iter().map(|table| table["table_name"].clone()).collect::<Vec<_>>()
+            });
+            responses.push(json!({
+                "put": uri,
+                "status": status,
+                "error": error,
+                "main_columns": main_columns,
+                "tables": tables,
+                "fields.csv": std::fs::read_to_string(&fields_csv).ok(),
+                "tables.csv": std::fs::read_to_string(&tables_csv).ok(),
+            }));
+        }
+        insta::assert_yaml_snapshot!(responses);
+    }
+
+    #[actix_web::test]
+    async fn presets_leave_job_files() {
+        let jobs_dir = tempfile::tempdir().unwrap();
+        let presets_dir = tempfile::tempdir().unwrap();
+        let preset_dir = presets_dir.path().join("titles");
+        std::fs::create_dir(&preset_dir).unwrap();
+        std::fs::write(preset_dir.join("preset.json"), "{}").unwrap();
+        std::fs::write(preset_dir.join("fields.csv"), "table_name,field_name,field_type,field_title\nmain,releaseDate,,Released\nmain,title,,Name\n").unwrap();
+        let config = Config { jobs_dir: jobs_dir.path().to_path_buf(), presets_dir: presets_dir.path().to_path_buf(), ..Default::default() };
+        let app = init_service(App::new().configure(test_api(config.clone()))).await;
+
+        let (_, preview) = status_and_body(call_service(&app, preview_request("basic.json").to_request()).await).await;
+        let id = preview["id"].as_str().unwrap();
+        let fields = json!([{"table_name": "main", "field_name": "title", "field_title": "Game Title"}, {"table_name": "main", "field_name": "id"}]);
+        let request = TestRequest::put().uri(&format!("/api/jobs/{}/fields", id)).set_json(fields);
+        assert_eq!(call_service(&app, request.to_request()).await.status().as_u16(), 200);
+        let fields_csv = std::fs::read_to_string(config.job_dir(id).join("fields.csv")).unwrap();
+
+        let mut responses = vec![];
+        for uri in [
+            format!("/api/jobs/{}/tables/main/rows?preset=titles", id),
+            format!("/api/jobs/{}/tables/main/rows", id),
+            format!("/api/convert?id={}&preset=titles&output_format=fields", id),
+        ] {
+            let (status, body) = status_and_body(call_service(&app, TestRequest::get().uri(&uri).to_request()).await).await;
+            assert_eq!(status, 200, "{}", body);
+            responses.extend(body.get("columns").cloned());
+            assert_eq!(std::fs::read_to_string(config.job_dir(id).join("fields.csv")).unwrap(), fields_csv, "after {}", uri);
+        }
+        assert!(!config.job_dir(id).join("tables.csv").exists());
+        insta::assert_yaml_snapshot!(responses);
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[actix_web::test]
+    async fn raw_bodies() {
+        let jobs_dir = tempfile::tempdir().unwrap();
+        let config = Config { jobs_dir: jobs_dir.path().to_path_buf(), max_size: 1, ..Default::default() };
+        let app = init_service(App::new().configure(test_api(config.clone()))).await;
+        let input = std::fs::read("fixtures/basic.json").unwrap();
+        let json_lines: Vec<u8> = serde_json::from_slice::<Vec<Value>>(&input)
+            .unwrap()
+            .iter()
+            .map(|record| format!("{}\n", record))
+            .collect::<String>()
+            .into_bytes();
+        // Small when sent, over the 1MB limit once decompressed.
+        let large = format!("[{}]", " ".repeat(1024 * 1024)).into_bytes();
+
+        let mut responses = vec![];
+        for (case, content_type, gzip_encoded, body) in [
+            ("json", "application/json", false, input.clone()),
+            ("gzip encoded json", "application/json", true, gzip(&input)),
+            ("gzip", "application/gzip", false, gzip(&input)),
+            ("json lines", "application/x-ndjson", false, json_lines.clone()),
+            ("form encoded", "application/x-www-form-urlencoded", false, input.clone()),
+            ("not gzip", "application/json", true, input.clone()),
+            ("over limit", "application/json", false, large.clone()),
+            ("over limit once decompressed", "application/gzip", false, gzip(&large)),
+            ("text", "text/plain", false, input.clone()),
+        ] {
+            let mut request = TestRequest::post()
+                .uri("/api/get_input?output_format=fields")
+                .insert_header((CONTENT_TYPE, content_type))
+                .set_payload(body);
+            if gzip_encoded {
+                request = request.insert_header((actix_web::http::header::CONTENT_ENCODING, "gzip"));
+            }
+            let response = call_service(&app, request.to_request()).await;
+            let id = response.headers().get(JOB_ID_HEADER).map(|id| id.to_str().unwrap().to_string());
+            let (status, body) = status_and_body(response).await;
+            let saved = id.map(|id| {
+                let saved = std::fs::read(config.job_dir(&id).join("download.json")).unwrap();
+                if saved == input {
+                    "input"
+                } else if saved == json_lines {
+                    "json lines"
+                } else {
+                    "other"
+                }
+            });
+            responses.push(json!({"case": case, "status": status, "error": body.get("error"), "saved": saved}));
+        }
+        insta::assert_yaml_snapshot!(responses);
+
+        // Bodies that could not be saved leave no partial input behind.
+        let inputs = std::fs::read_dir(jobs_dir.path())
+            .unwrap()
+            .filter(|job| job.as_ref().unwrap().path().join("download.json").exists())
+            .count();
+        assert_eq!(inputs, 5);
+    }
+
+    #[actix_web::test]
+    async fn accept_formats() {
+        let cases = [
+            None,
+            Some("text/csv"),
+            Some("application/vnd.sqlite3;q=0.5, text/csv;q=0.9"),
+            Some("application/vnd.openxmlformats-officedocument.spreadsheetml.sheet, text/csv"),
+            Some("application/x-sqlite3"),
+            Some("application/json, */*;q=0.1"),
+            Some("*/*"),
+            Some("text/*"),
+            Some("application/*"),
+            Some("text/csv;q=0, text/*, application/zip;q=0.5"),
+            Some("text/csv;q=0, */*;q=0"),
+            Some("application/json"),
+        ];
+        let formats: Vec<Value> = cases
+            .into_iter()
+            .map(|accept| {
+                let mut request = TestRequest::default();
+                if let Some(accept) = accept {
+                    request = request.insert_header((actix_web::http::header::ACCEPT, accept));
+                }
+                let mut query = Query::default();
+                let result = output_format_from_accept(&request.to_http_request(), &mut query);
+                match result {
+                    Ok(()) => json!({"accept": accept, "output_format": query.output_format}),
+                    Err(response) => json!({"accept": accept, "status": response.status().as_u16()}),
+                }
+            })
+            .collect();
+        insta::assert_yaml_snapshot!(formats);
+
+        // `output_format` wins over `Accept`.
+        let request = TestRequest::default().insert_header((actix_web::http::header::ACCEPT, "application/json"));
+        let mut query = Query { output_format: Some("xlsx".to_string()), ..Default::default() };
+        assert!(output_format_from_accept(&request.to_http_request(), &mut query).is_ok());
+        assert_eq!(query.output_format.as_deref(), Some("xlsx"));
+
+        let jobs_dir = tempfile::tempdir().unwrap();
+        let app = init_service(App::new().configure(test_api(Config { jobs_dir: jobs_dir.path().to_path_buf(), ..Default::default() }))).await;
+        let request = preview_request("basic.json").uri("/api/get_input").insert_header((actix_web::http::header::ACCEPT, "application/json"));
+        let (status, body) = status_and_body(call_service(&app, request.to_request()).await).await;
+        assert_eq!(status, 406);
+        insta::assert_yaml_snapshot!(body);
+        let request = preview_request("basic.json").uri("/api/get_input").insert_header((actix_web::http::header::ACCEPT, "text/csv"));
+        let response = call_service(&app, request.to_request()).await;
+        assert_eq!(response.status().as_u16(), 200);
+        assert!(response.headers().get(CONTENT_TYPE).unwrap().to_str().unwrap().starts_with("text/csv"));
+    }
+}
@@ -0,0 +1,246 @@
+use std::cell::RefCell;
+use std::io::Write;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use actix_web::body::{BodySize, EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::web::Bytes;
+use log::kv::{Key, Value as KvValue, VisitSource};
+use pin_project_lite::pin_project;
+use serde_json::{Map, Value};
+use uuid::Uuid;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+tokio::task_local! {
+    static CONTEXT: RefCell<LogContext>;
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct LogContext {
+    request_id: Option<String>,
+    job_id: Option<String>,
+}
+
+struct JsonFields<'a>(&'a mut Map<String, Value>);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: KvValue<'kvs>) -> Result<(), log::kv::Error> {
+        let value = serde_json::to_value(value).unwrap_or(Value::Null);
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+/// Log every record as a single line of JSON, tagged with the request and job id of the
+/// task that emitted it. Logs at `info` and above unless `RUST_LOG` says otherwise.
+pub fn init() {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"))
+        .format(|buf, record| {
+            let mut line = Map::new();
+            line.insert("timestamp".into(), buf.timestamp_millis().to_string().into());
+            line.insert("level".into(), record.level().as_str().into());
+            line.insert("target".into(), record.target().into());
+            line.insert("message".into(), record.args().to_string().into());
+
+            let context = current_context();
+            if let Some(request_id) = context.request_id {
+                line.insert("request_id".into(), request_id.into());
+            }
+            if let Some(job_id) = context.job_id {
+                line.insert("job_id".into(), job_id.into());
+            }
+
+            let _ = record.key_values().visit(&mut JsonFields(&mut line));
+
+            writeln!(buf, "{}", Value::Object(line))
+        })
+        .init();
+}
+
+/// Attach a job id to all further log lines of the current request.
+pub fn set_job_id(job_id: &str) {
+    let _ = CONTEXT.try_with(|context| context.borrow_mut().job_id = Some(job_id.to_string()));
+}
+
+/// The request and job ids of the current task, to carry over to another thread.
+pub fn current_context() -> LogContext {
+    CONTEXT.try_with(|context| context.borrow().clone()).unwrap_or_default()
+}
+
+/// Run `f` with the given request and job ids attached to its log lines.
+pub fn sync_scope<R>(context: LogContext, f: impl FnOnce() -> R) -> R {
+    CONTEXT.sync_scope(RefCell::new(context), f)
+}
+
+/// Render an error and all of its sources as `outer: inner: innermost`.
+pub fn error_chain(error: &(dyn std::error::Error + 'static)) -> String {
+    let mut chain = vec![error.to_string()];
+    let mut source = error.source();
+    while let Some(error) = source {
+        let message = error.to_string();
+        if !chain.contains(&message) {
+            chain.push(message);
+        }
+        source = error.source();
+    }
+    chain.join(": ")
+}
+
+fn valid_request_id(request_id: &str) -> bool {
+    !request_id.is_empty()
+        && request_id.len() <= 128
+        && request_id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+pin_project! {
+    /// The body of a downloaded output, logging how much of it was sent once the client has
+    /// it all or has gone away.
+    pub struct DownloadBody<B> {
+        #[pin]
+        body: B,
+        context: LogContext,
+        sent: u64,
+        finished: bool,
+    }
+
+    impl<B> PinnedDrop for DownloadBody<B> {
+        fn drop(this: Pin<&mut Self>) {
+            if !this.finished {
+                let bytes = this.sent;
+                sync_scope(this.context.clone(), || log::warn!(bytes = bytes; "download cancelled"));
+            }
+        }
+    }
+}
+
+impl<B: MessageBody> MessageBody for DownloadBody<B> {
+    type Error = B::Error;
+
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        let this = self.project();
+        let next = this.body.poll_next(cx);
+        match &next {
+            Poll::Ready(Some(Ok(chunk))) => *this.sent += chunk.len() as u64,
+            Poll::Ready(None) => {
+                *this.finished = true;
+                let bytes = *this.sent;
+                sync_scope(this.context.clone(), || log::info!(bytes = bytes; "download finished"));
+            }
+            _ => {}
+        }
+        next
+    }
+}
+
+fn is_download(res: &ServiceResponse<impl MessageBody>) -> bool {
+    res.headers()
+        .get(header::CONTENT_DISPOSITION)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("attachment"))
+}
+
+/// Middleware that takes the request id from the `x-request-id` header (or generates one),
+/// makes it available to every log line emitted while handling the request and echoes it
+/// back in the response. Downloads of outputs are logged as they start and finish.
+pub async fn request_context(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let request_id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| valid_request_id(value))
+        .map(|value| value.to_string())
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+
+    let context = RefCell::new(LogContext {
+        request_id: Some(request_id.clone()),
+        job_id: None,
+    });
+
+    CONTEXT
+        .scope(context, async move {
+            let start = Instant::now();
+            let method = req.method().to_string();
+            let path = req.path().to_string();
+
+            let mut res = next.call(req).await?;
+
+            log::info!(
+                method = method,
+                path = path,
+                status = res.status().as_u16(),
+                elapsed_ms = start.elapsed().as_millis() as u64;
+                "request finished"
+            );
+
+            if let Ok(value) = HeaderValue::from_str(&request_id) {
+                res.headers_mut().insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+            }
+
+            if !is_download(&res) {
+                return Ok(res.map_into_right_body());
+            }
+            let bytes = match res.response().body().size() {
+                BodySize::Sized(bytes) => Some(bytes),
+                _ => None,
+            };
+            log::info!(bytes = bytes; "download started");
+            let context = current_context();
+            Ok(res.map_body(|_, body| EitherBody::left(DownloadBody { body, context, sent: 0, finished: false })))
+        })
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::middleware::from_fn;
+    use actix_web::{test, web, App, HttpResponse};
+
+    /// Responds with the request id seen by the handler, and as a download when asked.
+    async fn handler(query: web::Query<std::collections::HashMap<String, String>>) -> HttpResponse {
+        let request_id = current_context().request_id.unwrap_or_default();
+        let mut response = HttpResponse::Ok();
+        if query.contains_key("download") {
+            response.insert_header((header::CONTENT_DISPOSITION, "attachment; filename=\"out.csv\""));
+        }
+        response.body(request_id)
+    }
+
+    #[actix_web::test]
+    async fn request_ids() {
+        let app = test::init_service(App::new().wrap(from_fn(request_context)).route("/", web::get().to(handler))).await;
+
+        let mut seen = vec![];
+        for (request_id, uri) in [(Some("abc-123.x_y"), "/"), (Some("not valid!"), "/"), (None, "/"), (Some("file-1"), "/?download")] {
+            let mut request = test::TestRequest::get().uri(uri);
+            if let Some(request_id) = request_id {
+                request = request.insert_header((REQUEST_ID_HEADER, request_id));
+            }
+            let response = test::call_service(&app, request.to_request()).await;
+            let echoed = response.headers().get(REQUEST_ID_HEADER).unwrap().to_str().unwrap().to_string();
+            let body = String::from_utf8(test::read_body(response).await.to_vec()).unwrap();
+            // The handler's log context has the same id as the response header.
+            assert_eq!(echoed, body);
+            seen.push((request_id, echoed));
+        }
+
+        assert_eq!(seen[0].1, "abc-123.x_y");
+        assert_eq!(seen[3].1, "file-1");
+        for (_, generated) in &seen[1..3] {
+            assert!(Uuid::parse_str(generated).is_ok(), "{}", generated);
+        }
+        assert_ne!(seen[1].1, seen[2].1);
+    }
+}
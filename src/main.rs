@@ -1,4 +1,46 @@
-use flatterer_web;
-fn main() -> std::io::Result<()>{
-    flatterer_web::main()
-}
\ No newline at end of file
+use clap::{Parser, Subcommand};
+use flatterer_web::batch::ConvertArgs;
+use flatterer_web::config::{Config, ServerArgs};
+
+#[derive(Parser, Debug)]
+#[command(name = "flatterer_web", version, about = "JSON flatterer UI")]
+struct Cli {
+    #[command(flatten)]
+    server: ServerArgs,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Convert a JSON file or URL without running the server.
+    Convert(ConvertArgs),
+}
+
+fn main() {
+    let cli = Cli::parse();
+
+    let config = match Config::load(&cli.server) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("Error in configuration: {:#}", err);
+            std::process::exit(2);
+        }
+    };
+
+    if cli.server.print_config {
+        print!("{}", config.to_toml());
+        return;
+    }
+
+    let result = match cli.command {
+        Some(Command::Convert(args)) => flatterer_web::batch::convert(&config, args),
+        None => flatterer_web::main(config),
+    };
+
+    if let Err(err) = result {
+        eprintln!("Error: {:#}", err);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,361 @@
+use std::path::{Path, PathBuf};
+
+use actix_multipart::form::tempfile::TempFile;
+use actix_multipart::form::MultipartForm;
+use actix_web::body::BoxBody;
+use actix_web::{web, FromRequest, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::config::Config;
+use crate::{bad_request_json, internal_error_json, not_found_json, Query};
+
+/// Files kept with a preset and flattened with when the preset is used.
+const PRESET_FILES: [(&str, &[&str]); 2] = [
+    ("fields.csv", &["table_name", "field_name"]),
+    ("tables.csv", &["table_name", "table_title"]),
+];
+
+/// The options of `Query` that a preset stores.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct PresetOptions {
+    array_key: Option<String>,
+    json_lines: Option<bool>,
+    main_table_name: Option<String>,
+    inline_one_to_one: Option<bool>,
+    json_schema: Option<String>,
+    table_prefix: Option<String>,
+    path_separator: Option<String>,
+    schema_titles: Option<String>,
+    fields_only: Option<bool>,
+    tables_only: Option<bool>,
+    pushdown: Option<String>,
+    validate: Option<bool>,
+}
+
+impl PresetOptions {
+    pub fn from_query(query: &Query) -> PresetOptions {
+        PresetOptions {
+            array_key: query.array_key.clone(),
+            json_lines: query.json_lines,
+            main_table_name: query.main_table_name.clone(),
+            inline_one_to_one: query.inline_one_to_one,
+            json_schema: query.json_schema.clone(),
+            table_prefix: query.table_prefix.clone(),
+            path_separator: query.path_separator.clone(),
+            schema_titles: query.schema_titles.clone(),
+            fields_only: query.fields_only,
+            tables_only: query.tables_only,
+            pushdown: query.pushdown.clone(),
+            validate: query.validate,
+        }
+    }
+
+    /// Fill in the options not given in `query`.
+    fn fill(self, query: &mut Query) {
+        query.array_key = query.array_key.take().or(self.array_key);
+        query.json_lines = query.json_lines.or(self.json_lines);
+        query.main_table_name = query.main_table_name.take().or(self.main_table_name);
+        query.inline_one_to_one = query.inline_one_to_one.or(self.inline_one_to_one);
+        query.json_schema = query.json_schema.take().or(self.json_schema);
+        query.table_prefix = query.table_prefix.take().or(self.table_prefix);
+        query.path_separator = query.path_separator.take().or(self.path_separator);
+        query.schema_titles = query.schema_titles.take().or(self.schema_titles);
+        query.fields_only = query.fields_only.or(self.fields_only);
+        query.tables_only = query.tables_only.or(self.tables_only);
+        query.pushdown = query.pushdown.take().or(self.pushdown);
+        query.validate = query.validate.or(self.validate);
+    }
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+fn preset_path(presets_dir: &Path, name: &str) -> Result<PathBuf, String> {
+    if !valid_name(name) {
+        return Err(format!("invalid preset name `{}`, use letters, numbers, `-` and `_`", name));
+    }
+    Ok(presets_dir.join(name))
+}
+
+fn read_options(preset_path: &Path) -> Result<PresetOptions, String> {
+    let contents = std::fs::read_to_string(preset_path.join("preset.json"))
+        .map_err(|e| format!("could not read preset: {}", e))?;
+    serde_json::from_str(&contents).map_err(|e| format!("invalid preset: {}", e))
+}
+
+/// Fill in the options of `query` not given in the request from the named preset, and have
+/// it flattened with the preset's `fields.csv` and `tables.csv` in place of the job's, apart
+/// from files named in `keep`. The job's own files are left as they are.
+pub fn apply(presets_dir: &Path, name: &str, query: &mut Query, keep: &[&str]) -> Result<(), String> {
+    let preset_path = preset_path(presets_dir, name)?;
+    if !preset_path.is_dir() {
+        return Err(format!("preset `{}` does not exist", name));
+    }
+    read_options(&preset_path)?.fill(query);
+
+    for (file_name, _) in PRESET_FILES {
+        let preset_file = preset_path.join(file_name);
+        if keep.contains(&file_name) || !preset_file.exists() {
+            continue;
+        }
+        if file_name == "fields.csv" {
+            query.fields_csv = Some(preset_file);
+        } else {
+            query.tables_csv = Some(preset_file);
+        }
+    }
+    log::info!(preset = name; "preset applied");
+    Ok(())
+}
+
+fn describe(preset_path: &Path, name: &str, with_files: bool) -> Result<Value, String> {
+    let mut preset = json!({"name": name, "options": read_options(preset_path)?});
+    for (file_name, _) in PRESET_FILES {
+        let file_path = preset_path.join(file_name);
+        preset[file_name] = if with_files {
+            std::fs::read_to_string(&file_path).ok().into()
+        } else {
+            file_path.exists().into()
+        };
+    }
+    Ok(preset)
+}
+
+pub async fn list_presets(config: web::Data<Config>) -> HttpResponse<BoxBody> {
+    let entries = match std::fs::read_dir(&config.presets_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return HttpResponse::Ok().body(json!({"presets": []}).to_string())
+        }
+        Err(e) => return internal_error_json(format!("Error reading presets: {:?}", e)),
+    };
+
+    let mut names: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().join("preset.json").exists())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| valid_name(name))
+        .collect();
+    names.sort();
+
+    let mut presets = vec![];
+    for name in names {
+        match describe(&config.presets_dir.join(&name), &name, false) {
+            Ok(preset) => presets.push(preset),
+            Err(error) => log::warn!(preset = name, error = error; "skipping unreadable preset"),
+        }
+    }
+    HttpResponse::Ok().body(json!({"presets": presets}).to_string())
+}
+
+pub async fn get_preset(config: web::Data<Config>, name: web::Path<String>) -> HttpResponse<BoxBody> {
+    let preset_path = match preset_path(&config.presets_dir, &name) {
+        Ok(preset_path) => preset_path,
+        Err(error) => return bad_request_json(json!({"error": error})),
+    };
+    if !preset_path.is_dir() {
+        return not_found_json(&format!("preset `{}` does not exist", name));
+    }
+    match describe(&preset_path, &name, true) {
+        Ok(preset) => HttpResponse::Ok().body(preset.to_string()),
+        Err(error) => internal_error_json(error),
+    }
+}
+
+#[derive(Debug, MultipartForm)]
+pub struct PresetForm {
+    #[multipart(limit = "100MB")]
+    fields: Option<TempFile>,
+    #[multipart(limit = "100MB")]
+    tables: Option<TempFile>,
+}
+
+fn check_csv_header(path: &Path, file_name: &str, required: &[&str]) -> Result<(), String> {
+    let mut reader = csv::Reader::from_path(path).map_err(|e| format!("could not read {}: {}", file_name, e))?;
+    let headers = reader.headers().map_err(|e| format!("could not read {}: {}", file_name, e))?;
+    for column in required {
+        if !headers.iter().any(|header| header == *column) {
+            return Err(format!("{} needs a `{}` column", file_name, column));
+        }
+    }
+    Ok(())
+}
+
+/// Create or replace a preset from the options in the query string. `fields` and `tables`
+/// can be uploaded as multipart parts, otherwise they are copied from the job given by `id`.
+pub async fn put_preset(
+    config: web::Data<Config>,
+    name: web::Path<String>,
+    query: web::Query<Query>,
+    req: HttpRequest,
+    payload: web::Payload,
+) -> HttpResponse<BoxBody> {
+    let preset_path = match preset_path(&config.presets_dir, &name) {
+        Ok(preset_path) => preset_path,
+        Err(error) => return bad_request_json(json!({"error": error})),
+    };
+
+    let is_multipart = req
+        .headers()
+        .get(actix_web::http::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("multipart/form-data"));
+    let form = if is_multipart {
+        match MultipartForm::<PresetForm>::from_request(&req, &mut payload.into_inner()).await {
+            Ok(MultipartForm(form)) => Some(form),
+            Err(e) => return bad_request_json(json!({"error": e.to_string()})),
+        }
+    } else {
+        None
+    };
+
+    let job_path = match &query.id {
+        Some(id) if uuid::Uuid::parse_str(id).is_ok() && config.job_dir(id).exists() => Some(config.job_dir(id)),
+        Some(_) => return not_found_json("id does not exist, you may need to ask you file to be downloaded again or to upload the file again."),
+        None => None,
+    };
+
+    if let Err(e) = std::fs::create_dir_all(&config.presets_dir) {
+        return internal_error_json(format!("Error creating presets dir: {:?}", e));
+    }
+    // Written next to the preset and moved into place once complete.
+    let tmp_dir = match tempfile::Builder::new().prefix(".tmp-").tempdir_in(&config.presets_dir) {
+        Ok(tmp_dir) => tmp_dir,
+        Err(e) => return internal_error_json(format!("Error creating temp dir: {:?}", e)),
+    };
+
+    let options = PresetOptions::from_query(&query);
+    let options_json = serde_json::to_string_pretty(&options).expect("options are always serializable");
+    if let Err(e) = std::fs::write(tmp_dir.path().join("preset.json"), options_json) {
+        return internal_error_json(format!("Error writing preset: {:?}", e));
+    }
+
+    let (mut fields, mut tables) = match form {
+        Some(form) => (form.fields, form.tables),
+        None => (None, None),
+    };
+    for (file_name, required) in PRESET_FILES {
+        let upload = if file_name == "fields.csv" { fields.take() } else { tables.take() };
+        let target = tmp_dir.path().join(file_name);
+        let result = match (upload, &job_path) {
+            // Copied rather than persisted, as uploads may be on another filesystem.
+            (Some(upload), _) => std::fs::copy(upload.file.path(), &target).map(|_| ()),
+            (None, Some(job_path)) if job_path.join(file_name).exists() => {
+                std::fs::copy(job_path.join(file_name), &target).map(|_| ())
+            }
+            _ => continue,
+        };
+        if let Err(e) = result {
+            return internal_error_json(format!("Error saving {}: {:?}", file_name, e));
+        }
+        if let Err(error) = check_csv_header(&target, file_name, required) {
+            return bad_request_json(json!({"error": error}));
+        }
+    }
+
+    let existed = preset_path.exists();
+    if existed {
+        if let Err(e) = std::fs::remove_dir_all(&preset_path) {
+            return internal_error_json(format!("Error replacing preset: {:?}", e));
+        }
+    }
+    if let Err(e) = std::fs::rename(tmp_dir.into_path(), &preset_path) {
+        return internal_error_json(format!("Error saving preset: {:?}", e));
+    }
+    log::info!(preset = name.as_str(), replaced = existed; "preset saved");
+
+    match describe(&preset_path, &name, false) {
+        Ok(preset) if existed => HttpResponse::Ok().body(preset.to_string()),
+        Ok(preset) => HttpResponse::Created().body(preset.to_string()),
+        Err(error) => internal_error_json(error),
+    }
+}
+
+pub async fn delete_preset(config: web::Data<Config>, name: web::Path<String>) -> HttpResponse<BoxBody> {
+    let preset_path = match preset_path(&config.presets_dir, &name) {
+        Ok(preset_path) => preset_path,
+        Err(error) => return bad_request_json(json!({"error": error})),
+    };
+    if !preset_path.is_dir() {
+        return not_found_json(&format!("preset `{}` does not exist", name));
+    }
+    if let Err(e) = std::fs::remove_dir_all(&preset_path) {
+        return internal_error_json(format!("Error deleting preset: {:?}", e));
+    }
+    log::info!(preset = name.as_str(); "preset deleted");
+    HttpResponse::Ok().body(json!({"name": name.as_str(), "deleted": true}).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn save_preset(presets_dir: &Path, name: &str, options: &PresetOptions, files: &[(&str, &str)]) {
+        let preset_path = presets_dir.join(name);
+        std::fs::create_dir_all(&preset_path).unwrap();
+        std::fs::write(preset_path.join("preset.json"), serde_json::to_string(options).unwrap()).unwrap();
+        for (file_name, contents) in files {
+            std::fs::write(preset_path.join(file_name), contents).unwrap();
+        }
+    }
+
+    #[test]
+    fn fill_keeps_request_options() {
+        let preset = PresetOptions {
+            array_key: Some("releases".to_string()),
+            json_lines: Some(false),
+            main_table_name: Some("release".to_string()),
+            inline_one_to_one: Some(true),
+            path_separator: Some("__".to_string()),
+            validate: Some(true),
+            ..Default::default()
+        };
+        let mut query = Query {
+            main_table_name: Some("main".to_string()),
+            inline_one_to_one: Some(false),
+            table_prefix: Some("ocds_".to_string()),
+            ..Default::default()
+        };
+        preset.fill(&mut query);
+        insta::assert_yaml_snapshot!(PresetOptions::from_query(&query));
+    }
+
+    #[test]
+    fn preset_names() {
+        let long = "a".repeat(65);
+        let names: Vec<(&str, bool)> = ["ocds", "my_preset-2", "", "../etc", "a b", "é", &long]
+            .into_iter()
+            .map(|name| (name, valid_name(name)))
+            .collect();
+        insta::assert_yaml_snapshot!(names);
+    }
+
+    #[test]
+    fn apply_uses_files() {
+        let presets_dir = tempfile::tempdir().unwrap();
+        let options = PresetOptions { array_key: Some("releases".to_string()), ..Default::default() };
+        save_preset(
+            presets_dir.path(),
+            "ocds",
+            &options,
+            &[("fields.csv", "table_name,field_name\n"), ("tables.csv", "table_name,table_title\n")],
+        );
+
+        let mut query = Query::default();
+        apply(presets_dir.path(), "ocds", &mut query, &["fields.csv"]).unwrap();
+        assert_eq!(query.array_key.as_deref(), Some("releases"));
+        assert_eq!(query.fields_csv, None);
+        assert_eq!(query.tables_csv, Some(presets_dir.path().join("ocds").join("tables.csv")));
+
+        let errors: Vec<String> = ["missing", "../ocds"]
+            .into_iter()
+            .map(|name| apply(presets_dir.path(), name, &mut Query::default(), &[]).unwrap_err())
+            .collect();
+        insta::assert_yaml_snapshot!(errors);
+    }
+}
@@ -0,0 +1,163 @@
+use std::path::Path;
+
+use serde::Serialize;
+
+/// Written into each output, one row per link from a child table to a parent table.
+pub const RELATIONSHIPS_FILE: &str = "relationships.csv";
+
+pub struct Field {
+    pub name: String,
+    pub title: String,
+    pub field_type: String,
+}
+
+pub struct Table {
+    pub name: String,
+    pub title: String,
+    pub fields: Vec<Field>,
+}
+
+impl Table {
+    /// The title of the `_link` column, if it is output.
+    pub fn link_title(&self) -> Option<&str> {
+        self.fields.iter().find(|field| field.name == "_link").map(|field| field.title.as_str())
+    }
+}
+
+/// Tables and fields as listed in an output's `fields.csv` and `tables.csv`, so edits to
+/// either are taken into account.
+pub fn read_tables(output_path: &Path) -> csv::Result<Vec<Table>> {
+    let mut tables = vec![];
+    for record in csv::Reader::from_path(output_path.join("tables.csv"))?.records() {
+        let record = record?;
+        tables.push(Table {
+            name: record.get(0).unwrap_or_default().to_string(),
+            title: record.get(1).unwrap_or_default().to_string(),
+            fields: vec![],
+        });
+    }
+
+    for record in csv::Reader::from_path(output_path.join("fields.csv"))?.records() {
+        let record = record?;
+        let table_name = record.get(0).unwrap_or_default();
+        if let Some(table) = tables.iter_mut().find(|table| table.name == table_name) {
+            let name = record.get(1).unwrap_or_default().to_string();
+            table.fields.push(Field {
+                title: record.get(3).filter(|title| !title.is_empty()).unwrap_or(&name).to_string(),
+                field_type: record.get(2).unwrap_or_default().to_string(),
+                name,
+            });
+        }
+    }
+    Ok(tables)
+}
+
+/// The tables that have a CSV file in the output, which excludes empty tables.
+pub fn output_tables(output_path: &Path) -> csv::Result<Vec<Table>> {
+    Ok(read_tables(output_path)?
+        .into_iter()
+        .filter(|table| output_path.join("csv").join(format!("{}.csv", table.title)).exists())
+        .collect())
+}
+
+/// A child table's `_link_<parent>` column joining to the parent's `_link` column. Tables are
+/// given by name and by title, which is also the name of their CSV file, and keys by their
+/// column titles.
+#[derive(Serialize, Debug, Clone)]
+pub struct Relationship {
+    pub parent_table: String,
+    pub parent_title: String,
+    pub parent_key: String,
+    pub child_table: String,
+    pub child_title: String,
+    pub child_key: String,
+}
+
+/// Every link between the tables, skipping those where either key column is not output.
+pub fn find(tables: &[Table]) -> Vec<Relationship> {
+    let mut relationships = vec![];
+    for child in tables {
+        for field in &child.fields {
+            let Some(parent_name) = field.name.strip_prefix("_link_") else {
+                continue;
+            };
+            let Some(parent) = tables.iter().find(|parent| parent.name == parent_name && parent.name != child.name) else {
+                continue;
+            };
+            let Some(parent_key) = parent.link_title() else {
+                continue;
+            };
+            relationships.push(Relationship {
+                parent_table: parent.name.clone(),
+                parent_title: parent.title.clone(),
+                parent_key: parent_key.to_string(),
+                child_table: child.name.clone(),
+                child_title: child.title.clone(),
+                child_key: field.title.clone(),
+            });
+        }
+    }
+    relationships
+}
+
+/// The relationships between the tables of an output.
+pub fn read(output_path: &Path) -> csv::Result<Vec<Relationship>> {
+    Ok(find(&read_tables(output_path)?))
+}
+
+pub fn write_csv(output_path: &Path) -> csv::Result<()> {
+    let mut writer = csv::Writer::from_path(output_path.join(RELATIONSHIPS_FILE))?;
+    // Written explicitly so the header is there when there are no relationships.
+    writer.write_record(["parent_table", "parent_title", "parent_key", "child_table", "child_title", "child_key"])?;
+    for relationship in read(output_path)? {
+        writer.write_record([
+            relationship.parent_table,
+            relationship.parent_title,
+            relationship.parent_key,
+            relationship.child_table,
+            relationship.child_title,
+            relationship.child_key,
+        ])?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::flatten_fixture;
+
+    fn table(name: &str, fields: &[&str]) -> Table {
+        Table {
+            name: name.to_string(),
+            title: format!("{} title", name),
+            fields: fields
+                .iter()
+                .map(|field| Field { name: field.to_string(), title: format!("{} title", field), field_type: "text".to_string() })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn fixture_relationships() {
+        let job_dir = flatten_fixture("basic.json");
+        let output_path = job_dir.path().join("output");
+        insta::assert_yaml_snapshot!(read(&output_path).unwrap());
+        insta::assert_snapshot!(std::fs::read_to_string(output_path.join(RELATIONSHIPS_FILE)).unwrap());
+    }
+
+    #[test]
+    fn relationships_need_both_keys() {
+        let tables = vec![
+            table("main", &["_link", "id"]),
+            table("items", &["_link", "_link_main"]),
+            // Its parent's `_link` is not output.
+            table("orphans", &["_link", "_link_unlinked"]),
+            table("unlinked", &["id"]),
+            // Links to itself and to a missing table are skipped.
+            table("loops", &["_link", "_link_loops", "_link_missing"]),
+        ];
+        insta::assert_yaml_snapshot!(find(&tables));
+    }
+}
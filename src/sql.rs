@@ -0,0 +1,214 @@
+use std::collections::HashSet;
+use std::fmt::Write;
+use std::path::Path;
+
+use crate::datapackage;
+use crate::relationships::{self, Relationship, Table};
+
+/// Directory within the output the SQL scripts are written to.
+pub const SQL_DIR: &str = "sql";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Dialect {
+    Postgresql,
+    Mysql,
+    Sqlite,
+}
+
+const DIALECTS: [Dialect; 3] = [Dialect::Postgresql, Dialect::Mysql, Dialect::Sqlite];
+
+impl Dialect {
+    fn file_name(self) -> &'static str {
+        match self {
+            Dialect::Postgresql => "postgresql.sql",
+            Dialect::Mysql => "mysql.sql",
+            Dialect::Sqlite => "sqlite.sql",
+        }
+    }
+
+    fn quote(self, identifier: &str) -> String {
+        match self {
+            Dialect::Mysql => format!("`{}`", identifier.replace('`', "``")),
+            _ => format!("\"{}\"", identifier.replace('"', "\"\"")),
+        }
+    }
+
+    /// The column type for a `fields.csv` type. MySQL cannot index `TEXT`, so its keys are
+    /// `VARCHAR`.
+    fn column_type(self, field_type: &str, datetime: bool, key: bool) -> &'static str {
+        match (self, field_type) {
+            (Dialect::Mysql, _) if key => "VARCHAR(255)",
+            (Dialect::Postgresql, "number") => "NUMERIC",
+            (Dialect::Postgresql, "integer") => "BIGINT",
+            (Dialect::Postgresql, "boolean") => "BOOLEAN",
+            (Dialect::Postgresql, "date") if datetime => "TIMESTAMPTZ",
+            (Dialect::Postgresql, "date") => "DATE",
+            (Dialect::Postgresql, "object" | "array") => "JSONB",
+            (Dialect::Mysql, "number") => "DOUBLE",
+            (Dialect::Mysql, "integer") => "BIGINT",
+            (Dialect::Mysql, "boolean") => "BOOLEAN",
+            (Dialect::Mysql, "date") if datetime => "DATETIME",
+            (Dialect::Mysql, "date") => "DATE",
+            (Dialect::Mysql, "object" | "array") => "JSON",
+            (Dialect::Sqlite, "number") => "NUMERIC",
+            (Dialect::Sqlite, "integer") => "INTEGER",
+            (Dialect::Sqlite, "boolean") => "BOOLEAN",
+            _ => "TEXT",
+        }
+    }
+}
+
+fn is_key(field_name: &str) -> bool {
+    field_name == "_link" || field_name.starts_with("_link_")
+}
+
+/// A string literal, for file paths in load statements.
+fn literal(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+/// `"column" TYPE` for each field of a table.
+fn column_definitions(dialect: Dialect, table: &Table, datetimes: &HashSet<(String, String)>) -> Vec<String> {
+    table
+        .fields
+        .iter()
+        .map(|field| {
+            let datetime = datetimes.contains(&(table.name.clone(), field.name.clone()));
+            format!("{} {}", dialect.quote(&field.title), dialect.column_type(&field.field_type, datetime, is_key(&field.name)))
+        })
+        .collect()
+}
+
+pub fn postgresql_columns(table: &Table, datetimes: &HashSet<(String, String)>) -> Vec<String> {
+    column_definitions(Dialect::Postgresql, table, datetimes)
+}
+
+pub fn postgresql_quote(identifier: &str) -> String {
+    Dialect::Postgresql.quote(identifier)
+}
+
+fn create_table(dialect: Dialect, table: &Table, relationships: &[Relationship], datetimes: &HashSet<(String, String)>) -> String {
+    let mut lines = vec![];
+    for (field, column) in table.fields.iter().zip(column_definitions(dialect, table, datetimes)) {
+        if field.name == "_link" {
+            lines.push(format!("    {} PRIMARY KEY", column));
+        } else {
+            lines.push(format!("    {}", column));
+        }
+    }
+    for relationship in relationships.iter().filter(|relationship| relationship.child_table == table.name) {
+        lines.push(format!(
+            "    FOREIGN KEY ({}) REFERENCES {} ({})",
+            dialect.quote(&relationship.child_key),
+            dialect.quote(&relationship.parent_title),
+            dialect.quote(&relationship.parent_key)
+        ));
+    }
+    format!("CREATE TABLE {} (\n{}\n);\n", dialect.quote(&table.title), lines.join(",\n"))
+}
+
+/// Loads a table's CSV file, relative to the top of the output, with the client of each
+/// database: `\copy` for psql, `LOAD DATA LOCAL` for mysql and `.import` for sqlite3.
+fn load_table(dialect: Dialect, table: &Table, datetimes: &HashSet<(String, String)>) -> String {
+    let csv_path = format!("csv/{}.csv", table.title);
+    let columns: Vec<String> = table.fields.iter().map(|field| dialect.quote(&field.title)).collect();
+    match dialect {
+        Dialect::Postgresql => format!(
+            "\\copy {} ({}) FROM {} WITH (FORMAT csv, HEADER true)\n",
+            dialect.quote(&table.title),
+            columns.join(", "),
+            literal(&csv_path)
+        ),
+        Dialect::Mysql => {
+            // Read into variables so empty cells become NULL, `true`/`false` are converted and
+            // a `Z` offset, which MySQL does not accept, is spelled out.
+            let variables: Vec<String> = (0..table.fields.len()).map(|index| format!("@v{}", index)).collect();
+            let assignments: Vec<String> = table
+                .fields
+                .iter()
+                .enumerate()
+                .map(|(index, field)| {
+                    let value = if field.field_type == "boolean" {
+                        format!("CASE @v{} WHEN 'true' THEN 1 WHEN 'false' THEN 0 END", index)
+                    } else if datetimes.contains(&(table.name.clone(), field.name.clone())) {
+                        format!("REPLACE(NULLIF(@v{}, ''), 'Z', '+00:00')", index)
+                    } else {
+                        format!("NULLIF(@v{}, '')", index)
+                    };
+                    format!("{} = {}", columns[index], value)
+                })
+                .collect();
+            format!(
+                "LOAD DATA LOCAL INFILE {} INTO TABLE {}\n    CHARACTER SET utf8mb4\n    FIELDS TERMINATED BY ',' OPTIONALLY ENCLOSED BY '\"' ESCAPED BY ''\n    LINES TERMINATED BY '\\n'\n    IGNORE 1 LINES\n    ({})\n    SET {};\n",
+                literal(&csv_path),
+                dialect.quote(&table.title),
+                variables.join(", "),
+                assignments.join(",\n        ")
+            )
+        }
+        Dialect::Sqlite => format!(".import --csv --skip 1 {} {}\n", literal(&csv_path), dialect.quote(&table.title)),
+    }
+}
+
+fn script(dialect: Dialect, tables: &[&Table], relationships: &[Relationship], datetimes: &HashSet<(String, String)>) -> String {
+    let mut out = String::new();
+    match dialect {
+        Dialect::Postgresql => out.push_str("-- Run with psql from the directory the output was unzipped into.\n\n"),
+        Dialect::Mysql => out.push_str("-- Run with `mysql --local-infile=1` from the directory the output was unzipped into.\n\n"),
+        Dialect::Sqlite => out.push_str("-- Run with sqlite3 from the directory the output was unzipped into.\nPRAGMA foreign_keys = ON;\n\n"),
+    }
+    for table in tables {
+        writeln!(out, "{}", create_table(dialect, table, relationships, datetimes)).unwrap();
+    }
+    for table in tables {
+        out.push_str(&load_table(dialect, table, datetimes));
+    }
+    out
+}
+
+/// Write a script per database into `SQL_DIR` of an output with CSV files, creating a table
+/// for each CSV file with columns typed from `fields.csv`, `_link` as the primary key and
+/// `_link_<table>` columns as foreign keys, then loading the CSV files into them.
+pub fn write_scripts(output_path: &Path) -> std::io::Result<()> {
+    let sql_path = output_path.join(SQL_DIR);
+    std::fs::create_dir_all(&sql_path)?;
+
+    let datetimes = datapackage::detected_datetimes(output_path);
+    let all_tables = relationships::output_tables(output_path)?;
+    let relationships = relationships::find(&all_tables);
+    let mut tables: Vec<&Table> = all_tables.iter().collect();
+    // Parents are created and loaded before the tables referencing them.
+    tables.sort_by_key(|table| relationships.iter().filter(|relationship| relationship.child_table == table.name).count());
+
+    for dialect in DIALECTS {
+        std::fs::write(sql_path.join(dialect.file_name()), script(dialect, &tables, &relationships, &datetimes))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::flatten_fixture;
+
+    fn script_file(output_path: &Path, dialect: Dialect) -> String {
+        std::fs::read_to_string(output_path.join(SQL_DIR).join(dialect.file_name())).unwrap()
+    }
+
+    #[test]
+    fn fixture_scripts() {
+        let job_dir = flatten_fixture("basic.json");
+        let output_path = job_dir.path().join("output");
+        write_scripts(&output_path).unwrap();
+
+        insta::assert_snapshot!("postgresql", script_file(&output_path, Dialect::Postgresql));
+        insta::assert_snapshot!("mysql", script_file(&output_path, Dialect::Mysql));
+        insta::assert_snapshot!("sqlite", script_file(&output_path, Dialect::Sqlite));
+    }
+
+    #[test]
+    fn quoting() {
+        let quoted: Vec<String> = DIALECTS.iter().map(|dialect| dialect.quote("odd \"name\" `here`")).collect();
+        insta::assert_yaml_snapshot!(quoted);
+    }
+}
@@ -0,0 +1,191 @@
+use std::path::Path;
+use std::sync::{Arc, OnceLock};
+
+use actix_web::{http::header, web, HttpRequest, HttpResponse};
+use eyre::{eyre, WrapErr};
+use rustls::pki_types::pem::PemObject;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{CertificateError, ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig, SignatureScheme};
+
+/// Build a rustls server config from PEM encoded certificate chain and private key files.
+pub fn server_config(cert_path: &Path, key_path: &Path) -> eyre::Result<ServerConfig> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| eyre!("could not read certificates from `{}`: {}", cert_path.display(), e))?;
+    if certs.is_empty() {
+        return Err(eyre!("no certificates found in `{}`", cert_path.display()));
+    }
+
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|e| eyre!("could not read private key from `{}`: {}", key_path.display(), e))?;
+
+    ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .wrap_err("certificate and private key do not match")
+}
+
+/// How the certificate of a PostgreSQL server is checked, following libpq's `sslmode`:
+/// not at all for `prefer` and `require`, its chain for `verify-ca`, and its chain and host
+/// name for `verify-full`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CertificateCheck {
+    None,
+    Chain,
+    Full,
+}
+
+/// Checks server certificates against the bundled web PKI roots and the certificates
+/// installed on the system, as far as its `CertificateCheck` asks.
+#[derive(Debug)]
+struct PostgresVerifier {
+    check: CertificateCheck,
+    webpki: Arc<WebPkiServerVerifier>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PostgresVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if self.check == CertificateCheck::None {
+            return Ok(ServerCertVerified::assertion());
+        }
+        match self.webpki.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now) {
+            Err(rustls::Error::InvalidCertificate(CertificateError::NotValidForName | CertificateError::NotValidForNameContext { .. }))
+                if self.check == CertificateCheck::Chain =>
+            {
+                Ok(ServerCertVerified::assertion())
+            }
+            result => result,
+        }
+    }
+
+    // The handshake signatures are always checked, whatever is checked of the certificate.
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+fn root_store() -> Arc<RootCertStore> {
+    static ROOTS: OnceLock<Arc<RootCertStore>> = OnceLock::new();
+    ROOTS
+        .get_or_init(|| {
+            let mut roots = RootCertStore::empty();
+            roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+            let native = rustls_native_certs::load_native_certs();
+            for error in &native.errors {
+                log::warn!(error = error.to_string(); "could not load system certificates");
+            }
+            roots.add_parsable_certificates(native.certs);
+            Arc::new(roots)
+        })
+        .clone()
+}
+
+/// The rustls client config for connecting to a PostgreSQL destination.
+pub fn client_config(check: CertificateCheck) -> eyre::Result<ClientConfig> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let webpki = WebPkiServerVerifier::builder_with_provider(root_store(), provider.clone()).build()?;
+    let verifier = PostgresVerifier { check, webpki, provider: provider.clone() };
+    Ok(ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth())
+}
+
+/// Port HTTPS is served on, used by the redirect listener.
+#[derive(Debug, Clone, Copy)]
+pub struct HttpsPort(pub u16);
+
+/// Permanently redirect any plain HTTP request to the same path over HTTPS.
+pub async fn redirect_to_https(req: HttpRequest, https_port: web::Data<HttpsPort>) -> HttpResponse {
+    let connection_info = req.connection_info();
+    let host = connection_info.host();
+    let host = match host.rsplit_once(':') {
+        Some((host, port)) if port.chars().all(|c| c.is_ascii_digit()) => host,
+        _ => host,
+    };
+
+    let path = req.uri().path_and_query().map(|path| path.as_str()).unwrap_or("/");
+    let location = if https_port.0 == 443 {
+        format!("https://{}{}", host, path)
+    } else {
+        format!("https://{}:{}{}", host, https_port.0, path)
+    };
+
+    HttpResponse::PermanentRedirect()
+        .insert_header((header::LOCATION, location))
+        .finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test::{call_service, init_service, TestRequest}, App};
+
+    #[actix_web::test]
+    async fn redirects() {
+        let mut locations = vec![];
+        for (https_port, host, uri) in [
+            (8443, "example.com:8080", "/api/convert?output_format=xlsx&id=1"),
+            (443, "example.com:80", "/"),
+            (443, "example.com", "/index.html"),
+            (8443, "[::1]:8080", "/a/b"),
+        ] {
+            let app = init_service(
+                App::new().app_data(web::Data::new(HttpsPort(https_port))).default_service(web::to(redirect_to_https)),
+            )
+            .await;
+            let request = TestRequest::get().uri(uri).insert_header((header::HOST, host)).to_request();
+            let response = call_service(&app, request).await;
+            assert_eq!(response.status(), 308);
+            locations.push(response.headers().get(header::LOCATION).unwrap().to_str().unwrap().to_string());
+        }
+        assert_eq!(
+            locations,
+            [
+                "https://example.com:8443/api/convert?output_format=xlsx&id=1",
+                "https://example.com/",
+                "https://example.com/index.html",
+                "https://[::1]:8443/a/b",
+            ]
+        );
+    }
+
+    #[test]
+    fn certificate_checks() {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let webpki = WebPkiServerVerifier::builder_with_provider(root_store(), provider.clone()).build().unwrap();
+        let not_a_certificate = CertificateDer::from(vec![0x30, 0x03, 0x02, 0x01, 0x01]);
+        let server_name = ServerName::try_from("db.example.com").unwrap();
+
+        let results: Vec<(CertificateCheck, bool)> = [CertificateCheck::None, CertificateCheck::Chain, CertificateCheck::Full]
+            .into_iter()
+            .map(|check| {
+                let verifier = PostgresVerifier { check, webpki: webpki.clone(), provider: provider.clone() };
+                let verified = verifier.verify_server_cert(&not_a_certificate, &[], &server_name, &[], UnixTime::now());
+                (check, verified.is_ok())
+            })
+            .collect();
+        assert_eq!(results, [(CertificateCheck::None, true), (CertificateCheck::Chain, false), (CertificateCheck::Full, false)]);
+    }
+}
@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+
+use serde_json::{json, Number, Value};
+
+/// Convert a CSV cell to JSON using the `field_type` from fields.csv. Empty cells are null and
+/// anything that does not parse as its type is kept as a string.
+pub fn typed_value(value: &str, field_type: &str) -> Value {
+    if value.is_empty() {
+        return Value::Null;
+    }
+    match field_type {
+        "integer" | "number" => {
+            if let Ok(integer) = value.parse::<i64>() {
+                return Value::Number(integer.into());
+            }
+            if let Some(number) = value.parse::<f64>().ok().and_then(Number::from_f64) {
+                return Value::Number(number);
+            }
+        }
+        "boolean" => match value {
+            "true" | "TRUE" | "True" => return Value::Bool(true),
+            "false" | "FALSE" | "False" => return Value::Bool(false),
+            _ => {}
+        },
+        "object" | "array" => {
+            if let Ok(parsed) = serde_json::from_str::<Value>(value) {
+                return parsed;
+            }
+        }
+        _ => {}
+    }
+    Value::String(value.to_string())
+}
+
+/// Column descriptions for a table's fields, in CSV order.
+pub fn columns(table_fields: &[HashMap<String, String>]) -> Vec<Value> {
+    table_fields
+        .iter()
+        .map(|field| {
+            json!({
+                "name": field.get("field_name"),
+                "title": field.get("field_title"),
+                "type": field.get("field_type"),
+            })
+        })
+        .collect()
+}
+
+/// Type each cell of `rows` by its field. Cells without a matching field are left as strings.
+pub fn typed_rows(rows: &[Vec<String>], table_fields: &[HashMap<String, String>]) -> Vec<Vec<Value>> {
+    rows.iter()
+        .map(|row| {
+            row.iter()
+                .enumerate()
+                .map(|(col_num, item)| {
+                    let field_type = table_fields
+                        .get(col_num)
+                        .and_then(|field| field.get("field_type"))
+                        .map(|field_type| field_type.as_str())
+                        .unwrap_or("text");
+                    typed_value(item, field_type)
+                })
+                .collect()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field(name: &str, field_type: &str) -> HashMap<String, String> {
+        HashMap::from([
+            ("field_name".to_string(), name.to_string()),
+            ("field_title".to_string(), name.to_uppercase()),
+            ("field_type".to_string(), field_type.to_string()),
+        ])
+    }
+
+    #[test]
+    fn typed_values() {
+        let values: Vec<(&str, &str, Value)> = [
+            ("", "number"),
+            ("12", "integer"),
+            ("-3", "number"),
+            ("1.5", "number"),
+            ("NaN", "number"),
+            ("12a", "integer"),
+            ("true", "boolean"),
+            ("FALSE", "boolean"),
+            ("yes", "boolean"),
+            ("{\"a\": [1, 2]}", "object"),
+            ("[1, \"b\"]", "array"),
+            ("[1,", "array"),
+            ("2020-01-01", "date"),
+            ("12", "text"),
+        ]
+        .into_iter()
+        .map(|(value, field_type)| (value, field_type, typed_value(value, field_type)))
+        .collect();
+        insta::assert_yaml_snapshot!(values);
+    }
+
+    #[test]
+    fn typed_table() {
+        let fields = vec![field("_link", "text"), field("id", "integer"), field("active", "boolean")];
+        let rows = vec![
+            vec!["0".to_string(), "1".to_string(), "true".to_string()],
+            vec!["1".to_string(), "".to_string(), "false".to_string(), "extra".to_string()],
+        ];
+        let table = json!({"columns": columns(&fields), "rows": typed_rows(&rows, &fields)});
+        insta::assert_yaml_snapshot!(table);
+    }
+}
@@ -0,0 +1,323 @@
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use actix_web::body::BoxBody;
+use actix_web::{web, HttpResponse};
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::{bad_request_json, clean_tmp, conflict_json, integrity, internal_error_json, logging, not_found_json, run_in_thread};
+
+/// Data received so far, moved to `download.json` when the upload is finalized.
+const PART_FILE: &str = "upload.part";
+/// Present while the upload is not finalized.
+const STATE_FILE: &str = "upload.json";
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct UploadState {
+    size: Option<u64>,
+    file_name: Option<String>,
+}
+
+/// Uploads with a chunk being written or being finalized, so they are not changed by two
+/// requests at once.
+#[derive(Debug, Default)]
+pub struct UploadLocks(Mutex<HashSet<String>>);
+
+struct UploadLock<'a> {
+    locks: &'a UploadLocks,
+    id: String,
+}
+
+impl UploadLocks {
+    fn lock(&self, id: &str) -> Option<UploadLock<'_>> {
+        let mut locked = self.0.lock().expect("upload locks poisoned");
+        if !locked.insert(id.to_string()) {
+            return None;
+        }
+        Some(UploadLock { locks: self, id: id.to_string() })
+    }
+}
+
+impl Drop for UploadLock<'_> {
+    fn drop(&mut self) {
+        self.locks.0.lock().expect("upload locks poisoned").remove(&self.id);
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CreateQuery {
+    /// Total size in bytes, if known up front.
+    size: Option<u64>,
+    filename: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ChunkQuery {
+    offset: u64,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct FinalizeQuery {
+    /// Hex SHA-256 of the whole upload.
+    sha256: String,
+}
+
+fn read_state(job_path: &Path) -> Option<UploadState> {
+    let contents = std::fs::read_to_string(job_path.join(STATE_FILE)).ok()?;
+    serde_json::from_str(&contents).ok()
+}
+
+fn received(job_path: &Path) -> std::io::Result<u64> {
+    Ok(job_path.join(PART_FILE).metadata()?.len())
+}
+
+/// The job directory of an unfinished upload, or the response to return instead.
+fn open_upload(config: &Config, id: &str) -> Result<(PathBuf, UploadState), HttpResponse<BoxBody>> {
+    let job_path = config.job_dir(id);
+    if Uuid::parse_str(id).is_err() || !job_path.exists() {
+        return Err(not_found_json("upload does not exist, it may have expired"));
+    }
+    logging::set_job_id(id);
+    match read_state(&job_path) {
+        Some(state) => Ok((job_path, state)),
+        None => Err(conflict_json(json!({"id": id, "error": "upload is already finalized"}))),
+    }
+}
+
+/// Appends the chunks of a request to the upload from `offset` on the calling thread,
+/// returning the offset after them. If they would take the upload over `max_size` none of
+/// them are kept, so the upload stays at a known offset.
+fn append_chunks(part_path: &Path, offset: u64, max_size: u64, chunks: impl Iterator<Item = web::Bytes>) -> std::io::Result<u64> {
+    let mut file = OpenOptions::new().append(true).open(part_path)?;
+    let mut written = offset;
+    for chunk in chunks {
+        if written + chunk.len() as u64 > max_size {
+            file.set_len(offset)?;
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Upload Size Exceeded, maximum allowed is {} bytes", max_size),
+            ));
+        }
+        file.write_all(&chunk)?;
+        written += chunk.len() as u64;
+    }
+    Ok(written)
+}
+
+/// Start a chunked upload, returning the id to send chunks to.
+pub async fn create_upload(config: web::Data<Config>, query: web::Query<CreateQuery>) -> HttpResponse<BoxBody> {
+    if query.size.is_some_and(|size| size > config.max_size_bytes()) {
+        return bad_request_json(json!({"error": format!("Upload Size Exceeded, maximum allowed is {} bytes", config.max_size_bytes())}));
+    }
+    if let Err(e) = clean_tmp(&config) {
+        return internal_error_json(format!("Error cleaning tmp dir: {:?}", e));
+    }
+
+    let id = Uuid::new_v4().hyphenated().to_string();
+    logging::set_job_id(&id);
+    let job_path = config.job_dir(&id);
+    let state = UploadState { size: query.size, file_name: query.filename.clone() };
+    let created = std::fs::create_dir(&job_path)
+        .and_then(|_| File::create(job_path.join(PART_FILE)))
+        .and_then(|_| std::fs::write(job_path.join(STATE_FILE), json!(state).to_string()));
+    if let Err(e) = created {
+        return internal_error_json(format!("Error creating upload: {:?}", e));
+    }
+
+    log::info!(size = query.size; "upload created");
+    HttpResponse::Created().body(json!({"id": id, "offset": 0, "size": query.size}).to_string())
+}
+
+/// How much of an upload has been received, to know where to resume from.
+pub async fn upload_status(config: web::Data<Config>, id: web::Path<String>) -> HttpResponse<BoxBody> {
+    let (job_path, state) = match open_upload(&config, &id) {
+        Ok(upload) => upload,
+        Err(response) => return response,
+    };
+    match received(&job_path) {
+        Ok(offset) => HttpResponse::Ok().body(json!({"id": id.as_str(), "offset": offset, "size": state.size}).to_string()),
+        Err(e) => internal_error_json(format!("Error reading upload: {:?}", e)),
+    }
+}
+
+/// Append the request body to an upload. `offset` has to be the number of bytes received so
+/// far, so a chunk that was partly received can be resent from where it stopped.
+pub async fn put_chunk(
+    config: web::Data<Config>,
+    locks: web::Data<UploadLocks>,
+    id: web::Path<String>,
+    query: web::Query<ChunkQuery>,
+    mut payload: web::Payload,
+) -> HttpResponse<BoxBody> {
+    let (job_path, state) = match open_upload(&config, &id) {
+        Ok(upload) => upload,
+        Err(response) => return response,
+    };
+    let Some(_lock) = locks.lock(&id) else {
+        return conflict_json(json!({"id": id.as_str(), "error": "another chunk of this upload is being written"}));
+    };
+
+    let offset = match received(&job_path) {
+        Ok(offset) => offset,
+        Err(e) => return internal_error_json(format!("Error reading upload: {:?}", e)),
+    };
+    if query.offset != offset {
+        return conflict_json(json!({"id": id.as_str(), "error": format!("expected offset {}", offset), "offset": offset}));
+    }
+
+    let max_size = state.size.unwrap_or(u64::MAX).min(config.max_size_bytes());
+    let (mut sender, receiver) = futures::channel::mpsc::channel::<web::Bytes>(16);
+    let receive = async move {
+        while let Some(chunk) = payload.next().await {
+            // The writer has stopped, its error is the one returned.
+            if sender.send(chunk?).await.is_err() {
+                break;
+            }
+        }
+        Ok::<_, actix_web::error::PayloadError>(())
+    };
+    let part_path = job_path.join(PART_FILE);
+    let write = run_in_thread(move || append_chunks(&part_path, offset, max_size, futures::executor::block_on_stream(receiver)));
+
+    let written = match futures::join!(receive, write) {
+        (_, Err(e)) if e.kind() == std::io::ErrorKind::InvalidInput => {
+            return bad_request_json(json!({"id": id.as_str(), "error": e.to_string(), "offset": offset}));
+        }
+        (_, Err(e)) => return internal_error_json(format!("Error writing upload: {:?}", e)),
+        (Err(e), Ok(written)) => {
+            log::warn!(error = e.to_string(), offset = written; "chunk interrupted");
+            return bad_request_json(json!({"id": id.as_str(), "error": e.to_string(), "offset": written}));
+        }
+        (Ok(()), Ok(written)) => written,
+    };
+
+    // Chunks only change the file, so keep the directory from being cleaned up as stale.
+    let _ = File::open(&job_path).and_then(|dir| dir.set_modified(SystemTime::now()));
+
+    log::debug!(offset = offset, bytes = written - offset; "chunk received");
+    HttpResponse::Ok().body(json!({"id": id.as_str(), "offset": written, "size": state.size}).to_string())
+}
+
+/// Check the upload is complete and matches `sha256`, then make it the job's input so it can
+/// be converted with `id`.
+pub async fn finalize_upload(
+    config: web::Data<Config>,
+    locks: web::Data<UploadLocks>,
+    id: web::Path<String>,
+    query: web::Query<FinalizeQuery>,
+) -> HttpResponse<BoxBody> {
+    let (job_path, state) = match open_upload(&config, &id) {
+        Ok(upload) => upload,
+        Err(response) => return response,
+    };
+    let Some(_lock) = locks.lock(&id) else {
+        return conflict_json(json!({"id": id.as_str(), "error": "a chunk of this upload is being written"}));
+    };
+
+    let part_path = job_path.join(PART_FILE);
+    let size = match received(&job_path) {
+        Ok(size) => size,
+        Err(e) => return internal_error_json(format!("Error reading upload: {:?}", e)),
+    };
+    if state.size.is_some_and(|expected| expected != size) {
+        return bad_request_json(json!({"id": id.as_str(), "error": "upload is not complete", "offset": size}));
+    }
+
+    let hash_path = part_path.clone();
+    let sha256 = match web::block(move || integrity::sha256_file(&hash_path)).await {
+        Ok(Ok(sha256)) => sha256,
+        Ok(Err(e)) => return internal_error_json(format!("Error reading upload: {:?}", e)),
+        Err(e) => return internal_error_json(format!("Error reading upload: {:?}", e)),
+    };
+    if !sha256.eq_ignore_ascii_case(query.sha256.trim()) {
+        return bad_request_json(json!({"id": id.as_str(), "error": "sha256 does not match the uploaded data", "sha256": sha256}));
+    }
+
+    if let Err(e) = std::fs::rename(&part_path, job_path.join("download.json")) {
+        return internal_error_json(format!("Error finalizing upload: {:?}", e));
+    }
+    if let Err(e) = integrity::record_input(&job_path, state.file_name.as_deref(), Some(sha256.clone())) {
+        return internal_error_json(format!("Error finalizing upload: {:?}", e));
+    }
+    if let Err(e) = std::fs::remove_file(job_path.join(STATE_FILE)) {
+        return internal_error_json(format!("Error finalizing upload: {:?}", e));
+    }
+
+    log::info!(bytes = size; "upload finalized");
+    HttpResponse::Ok().body(json!({"id": id.as_str(), "size": size, "sha256": sha256}).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, App};
+    use serde_json::Value;
+
+    async fn status_and_body(response: actix_web::dev::ServiceResponse) -> (u16, Value) {
+        let status = response.status().as_u16();
+        let body: Value = test::read_body_json(response).await;
+        (status, body)
+    }
+
+    #[actix_web::test]
+    async fn chunked_upload() {
+        // Job directories, and the clean up of stale ones, stay inside the test's own directory.
+        let jobs_dir = tempfile::tempdir().unwrap();
+        let config = Config { max_size: 1, jobs_dir: jobs_dir.path().to_path_buf(), ..Default::default() };
+        let job_dir = |id: &str| config.job_dir(id);
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(config.clone()))
+                .app_data(web::Data::new(UploadLocks::default()))
+                .route("/api/uploads", web::post().to(create_upload))
+                .route("/api/uploads/{id}", web::get().to(upload_status))
+                .route("/api/uploads/{id}", web::put().to(put_chunk))
+                .route("/api/uploads/{id}/finalize", web::post().to(finalize_upload)),
+        )
+        .await;
+
+        let data = br#"[{"id": 1}, {"id": 2}]"#;
+        let request = test::TestRequest::post().uri(&format!("/api/uploads?size={}&filename=in.json", data.len()));
+        let (status, created) = status_and_body(test::call_service(&app, request.to_request()).await).await;
+        assert_eq!(status, 201);
+        let id = created["id"].as_str().unwrap().to_string();
+        let upload = format!("/api/uploads/{}", id);
+
+        let mut responses = vec![];
+        for request in [
+            test::TestRequest::put().uri(&format!("{}?offset=0", upload)).set_payload(&data[..10]),
+            // Resent from the start after the first chunk was received.
+            test::TestRequest::put().uri(&format!("{}?offset=0", upload)).set_payload(&data[..10]),
+            // Past the size given when the upload was created.
+            test::TestRequest::put().uri(&format!("{}?offset=10", upload)).set_payload(&[b' '; 20][..]),
+            test::TestRequest::get().uri(&upload),
+            test::TestRequest::post().uri(&format!("{}/finalize?sha256=00", upload)),
+            test::TestRequest::put().uri(&format!("{}?offset=10", upload)).set_payload(&data[10..]),
+            test::TestRequest::post().uri(&format!("{}/finalize?sha256=00", upload)),
+        ] {
+            responses.push(status_and_body(test::call_service(&app, request.to_request()).await).await);
+        }
+
+        let sha256 = integrity::sha256_file(&job_dir(&id).join(PART_FILE)).unwrap();
+        for request in [
+            test::TestRequest::post().uri(&format!("{}/finalize?sha256={}", upload, sha256.to_uppercase())),
+            test::TestRequest::put().uri(&format!("{}?offset=22", upload)).set_payload(&b"[]"[..]),
+        ] {
+            responses.push(status_and_body(test::call_service(&app, request.to_request()).await).await);
+        }
+        assert_eq!(std::fs::read(job_dir(&id).join("download.json")).unwrap(), data);
+
+        insta::assert_yaml_snapshot!(responses, {
+            "[][1].id" => "[id]",
+            "[][1].sha256" => "[sha256]",
+        });
+    }
+}
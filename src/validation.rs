@@ -0,0 +1,371 @@
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use jsonschema::Validator;
+use serde::de::value::MapAccessDeserializer;
+use serde::de::{DeserializeSeed, Deserializer, Error, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+/// Name of the table the validation errors are written to.
+pub const VALIDATION_TABLE: &str = "validation_errors";
+
+/// Errors kept for the `validation_errors` table, later ones are only counted.
+const MAX_ERRORS: usize = 10_000;
+
+/// Errors included in the summary returned with a preview.
+const SUMMARY_ERRORS: usize = 20;
+
+#[derive(Debug, Clone)]
+pub struct RecordError {
+    /// Index of the record in the input, matching `_link` of the main table.
+    pub record: usize,
+    /// JSON pointer to the invalid value within the record.
+    pub pointer: String,
+    pub message: String,
+}
+
+#[derive(Debug, Default)]
+pub struct ValidationReport {
+    pub records: usize,
+    pub invalid_records: usize,
+    pub error_count: usize,
+    pub errors: Vec<RecordError>,
+}
+
+impl ValidationReport {
+    fn check(&mut self, validator: &Validator, record: &Value) {
+        let index = self.records;
+        self.records += 1;
+
+        let mut invalid = false;
+        for error in validator.iter_errors(record) {
+            invalid = true;
+            self.error_count += 1;
+            if self.errors.len() < MAX_ERRORS {
+                self.errors.push(RecordError {
+                    record: index,
+                    pointer: error.instance_path.as_str().to_string(),
+                    message: error.to_string(),
+                });
+            }
+        }
+        if invalid {
+            self.invalid_records += 1;
+        }
+    }
+
+    pub fn truncated(&self) -> bool {
+        self.error_count > self.errors.len()
+    }
+
+    /// Counts and the first few errors, as returned with a preview and written to
+    /// `validation.json`.
+    pub fn summary(&self) -> Value {
+        let errors: Vec<Value> = self
+            .errors
+            .iter()
+            .take(SUMMARY_ERRORS)
+            .map(|error| json!({"record": error.record, "pointer": error.pointer, "message": error.message}))
+            .collect();
+        json!({
+            "valid": self.error_count == 0,
+            "records": self.records,
+            "invalid_records": self.invalid_records,
+            "error_count": self.error_count,
+            "truncated": self.truncated(),
+            "errors": errors,
+        })
+    }
+}
+
+/// The summary written by `write_report`, if the output was validated.
+pub fn read_summary(output_path: &Path) -> Option<Value> {
+    let file = File::open(output_path.join("validation.json")).ok()?;
+    serde_json::from_reader(BufReader::new(file)).ok()
+}
+
+/// Reads the schema to validate against from a URL or path, as accepted by `json_schema`.
+/// Fetching a URL blocks on a runtime of its own, so this must be called off the async
+/// runtime, as `run_flatterer` is.
+pub fn load_schema(location: &str) -> Result<Value, String> {
+    if location.starts_with("http") {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(|e| format!("could not fetch schema: {}", e))?;
+        return runtime.block_on(async {
+            let response = reqwest::get(location)
+                .await
+                .and_then(|response| response.error_for_status())
+                .map_err(|e| format!("could not fetch schema: {}", e))?;
+            let body = response.bytes().await.map_err(|e| format!("could not fetch schema: {}", e))?;
+            serde_json::from_slice(&body).map_err(|e| format!("schema is not valid JSON: {}", e))
+        });
+    }
+    let file = File::open(location).map_err(|e| format!("could not read schema: {}", e))?;
+    serde_json::from_reader(BufReader::new(file)).map_err(|e| format!("schema is not valid JSON: {}", e))
+}
+
+struct Records<'a> {
+    validator: &'a Validator,
+    report: &'a mut ValidationReport,
+}
+
+impl<'de> DeserializeSeed<'de> for Records<'_> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de> Visitor<'de> for Records<'_> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an array of records or a single record")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        while let Some(record) = seq.next_element::<Value>()? {
+            self.report.check(self.validator, &record);
+        }
+        Ok(())
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<(), A::Error> {
+        let record = Value::deserialize(MapAccessDeserializer::new(map))?;
+        self.report.check(self.validator, &record);
+        Ok(())
+    }
+}
+
+/// The records in the array under `path` of a top level object, skipping its other keys.
+struct PathRecords<'a> {
+    path: &'a str,
+    validator: &'a Validator,
+    report: &'a mut ValidationReport,
+}
+
+/// Only accepts an array of records, as for the value under `path`.
+struct RecordArray<'a>(Records<'a>);
+
+impl<'de> DeserializeSeed<'de> for RecordArray<'_> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de> Visitor<'de> for RecordArray<'_> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an array of records")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<(), A::Error> {
+        self.0.visit_seq(seq)
+    }
+}
+
+impl<'de> DeserializeSeed<'de> for PathRecords<'_> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de> Visitor<'de> for PathRecords<'_> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "an object with `{}` as an array of records", self.path)
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        let mut found = false;
+        while let Some(key) = map.next_key::<String>()? {
+            if key == self.path && !found {
+                found = true;
+                map.next_value_seed(RecordArray(Records { validator: self.validator, report: &mut *self.report }))?;
+            } else {
+                map.next_value::<IgnoredAny>()?;
+            }
+        }
+        if !found {
+            return Err(A::Error::custom(format!("`{}` is not an array in the input", self.path)));
+        }
+        Ok(())
+    }
+}
+
+/// Validate each record of the input against `schema`. Records are read the same way
+/// they are flattened: one per line for JSON lines, the items of the `path` array, or the
+/// items of a top level array. Arrays are streamed rather than read into memory.
+pub fn validate_input(input: &Path, schema: &Value, path: &str, json_lines: bool) -> Result<ValidationReport, String> {
+    let validator = jsonschema::validator_for(schema).map_err(|e| format!("invalid JSON Schema: {}", e))?;
+    let reader = BufReader::new(File::open(input).map_err(|e| format!("could not read input: {}", e))?);
+    let mut report = ValidationReport::default();
+
+    if json_lines {
+        for record in serde_json::Deserializer::from_reader(reader).into_iter::<Value>() {
+            let record = record.map_err(|e| format!("could not read input: {}", e))?;
+            report.check(&validator, &record);
+        }
+    } else if !path.is_empty() {
+        let mut deserializer = serde_json::Deserializer::from_reader(reader);
+        PathRecords { path, validator: &validator, report: &mut report }
+            .deserialize(&mut deserializer)
+            .map_err(|e| format!("could not read input: {}", e))?;
+    } else {
+        let mut deserializer = serde_json::Deserializer::from_reader(reader);
+        Records { validator: &validator, report: &mut report }
+            .deserialize(&mut deserializer)
+            .map_err(|e| format!("could not read input: {}", e))?;
+    }
+    Ok(report)
+}
+
+fn table_resource(name: &str, title: &str, row_count: usize) -> Value {
+    let field = |field_name: &str, field_type: &str| {
+        json!({
+            "name": field_name,
+            "title": field_name,
+            "type": field_type,
+            "format": field_type,
+            "count": row_count,
+        })
+    };
+    json!({
+        "profile": "tabular-data-resource",
+        "name": name,
+        "flatterer_name": name,
+        "title": title,
+        "schema": {
+            "fields": [field("record", "integer"), field("pointer", "string"), field("message", "string")]
+        },
+        "path": format!("csv/{}.csv", title),
+    })
+}
+
+fn other_error(error: impl fmt::Display) -> std::io::Error {
+    std::io::Error::other(error.to_string())
+}
+
+/// Adds the errors in `report` as a table to a flatterer output: its CSV, `datapackage.json`,
+/// `fields.csv` and `tables.csv`, then the sqlite output when `sqlite` is set. The xlsx
+/// output is made from the CSV files afterwards so it picks the table up. Also writes the
+/// summary to `validation.json`.
+pub fn write_report(output_path: &Path, report: &ValidationReport, table_prefix: &str, sqlite: bool) -> std::io::Result<()> {
+    std::fs::write(output_path.join("validation.json"), report.summary().to_string())?;
+
+    if report.errors.is_empty() {
+        return Ok(());
+    }
+
+    let datapackage_path = output_path.join("datapackage.json");
+    let mut datapackage: Value = serde_json::from_reader(BufReader::new(File::open(&datapackage_path)?))?;
+    let resources = datapackage["resources"]
+        .as_array_mut()
+        .ok_or_else(|| other_error("datapackage.json has no resources"))?;
+
+    let mut title = format!("{}{}", table_prefix, VALIDATION_TABLE);
+    while resources.iter().any(|resource| resource["title"] == title.as_str() || resource["name"] == title.as_str()) {
+        title.push('_');
+    }
+
+    std::fs::create_dir_all(output_path.join("csv"))?;
+    let mut writer = csv::Writer::from_path(output_path.join("csv").join(format!("{}.csv", title)))?;
+    writer.write_record(["record", "pointer", "message"])?;
+    for error in &report.errors {
+        writer.write_record([error.record.to_string().as_str(), &error.pointer, &error.message])?;
+    }
+    writer.flush()?;
+
+    let resource = table_resource(&title, &title, report.errors.len());
+    resources.push(resource.clone());
+    std::fs::write(&datapackage_path, serde_json::to_string_pretty(&datapackage)?)?;
+    libflatterer::write_metadata_csvs_from_datapackage(output_path.to_path_buf()).map_err(other_error)?;
+
+    if sqlite {
+        // Only the new table is added to the existing database.
+        let table_datapackage = output_path.join("validation_datapackage.json");
+        std::fs::write(&table_datapackage, json!({"profile": "tabular-data-package", "resources": [resource]}).to_string())?;
+        let result = csvs_convert::datapackage_to_sqlite(
+            output_path.join("sqlite.db").to_string_lossy().into(),
+            table_datapackage.to_string_lossy().into(),
+        );
+        std::fs::remove_file(&table_datapackage)?;
+        result.map_err(other_error)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "id": {"type": "integer"},
+                "tags": {"type": "array", "items": {"type": "string"}}
+            },
+            "required": ["id"]
+        })
+    }
+
+    fn validate(contents: &str, path: &str, json_lines: bool) -> Result<Value, String> {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("download.json");
+        std::fs::write(&input, contents).unwrap();
+        validate_input(&input, &schema(), path, json_lines).map(|report| report.summary())
+    }
+
+    #[test]
+    fn top_level_array() {
+        let summary = validate(r#"[{"id": 1}, {"id": "2", "tags": ["a", 3]}, {"tags": []}]"#, "", false);
+        insta::assert_yaml_snapshot!(summary);
+    }
+
+    #[test]
+    fn single_object() {
+        let summary = validate(r#"{"id": 1.5}"#, "", false);
+        insta::assert_yaml_snapshot!(summary);
+    }
+
+    #[test]
+    fn array_key() {
+        let input = r#"{"meta": {"id": "not a record"}, "records": [{"id": 1}, {"id": "two"}], "after": [{"id": "x"}]}"#;
+        insta::assert_yaml_snapshot!("array_key", validate(input, "records", false));
+        insta::assert_yaml_snapshot!("array_key_missing", validate(input, "missing", false));
+        insta::assert_yaml_snapshot!("array_key_not_array", validate(input, "meta", false));
+    }
+
+    #[test]
+    fn json_lines() {
+        let summary = validate("{\"id\": 1}\n{\"id\": null}\n\n{\"id\": 3}\n", "", true);
+        insta::assert_yaml_snapshot!(summary);
+    }
+
+    #[test]
+    fn invalid_json() {
+        insta::assert_yaml_snapshot!(validate(r#"[{"id": 1}, {"id": "#, "", false));
+    }
+
+    #[test]
+    fn errors_are_capped() {
+        let input = format!("[{}]", vec![r#"{"id": "x"}"#; MAX_ERRORS + 5].join(","));
+        let summary = validate(&input, "", false).unwrap();
+        assert_eq!(summary["error_count"], MAX_ERRORS + 5);
+        assert_eq!(summary["truncated"], true);
+        assert_eq!(summary["errors"].as_array().unwrap().len(), SUMMARY_ERRORS);
+    }
+}
@@ -0,0 +1,750 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use rust_xlsxwriter::{ExcelDateTime, Format, Workbook, Worksheet, XlsxError};
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::relationships::{self, Table};
+use crate::Query;
+
+/// Name of the workbook written into the output.
+pub const XLSX_FILE: &str = "output.xlsx";
+
+/// Rows in a sheet, including the header row.
+pub const EXCEL_MAX_ROWS: u64 = 1_048_576;
+pub const EXCEL_MAX_COLUMNS: usize = 16_384;
+const EXCEL_MAX_CELL_LENGTH: usize = 32_767;
+const EXCEL_MAX_SHEET_NAME: usize = 31;
+
+const README_SHEET: &str = "README";
+const WARNINGS_SHEET: &str = "Warnings";
+
+/// Auto-fitted columns are no wider than this, in characters.
+const MAX_COLUMN_WIDTH: usize = 60;
+
+/// Integers further from zero than this lose digits as an Excel number, a double.
+const MAX_EXACT_INTEGER: i128 = 1 << 53;
+
+/// The `xlsx_*` options of a conversion, and the ones naming tables and fields.
+#[derive(Debug, Clone)]
+pub struct XlsxOptions {
+    /// Keep the header row in view when scrolling.
+    pub freeze_header: bool,
+    /// Size columns to their contents.
+    pub autofit: bool,
+    /// Add a sheet describing each table and field.
+    pub readme: bool,
+    /// Continue tables with more rows than a sheet holds on further sheets.
+    pub split: bool,
+    /// Rows in a sheet, including the header row.
+    max_rows: u64,
+    main_table_name: String,
+    table_prefix: String,
+    path_separator: String,
+}
+
+impl XlsxOptions {
+    pub fn from_query(query: &Query) -> XlsxOptions {
+        XlsxOptions {
+            freeze_header: query.xlsx_freeze_header.unwrap_or(true),
+            autofit: query.xlsx_autofit.unwrap_or(true),
+            readme: query.xlsx_readme.unwrap_or(false),
+            split: query.xlsx_split.unwrap_or(false),
+            max_rows: EXCEL_MAX_ROWS,
+            main_table_name: query.main_table_name.clone().unwrap_or_else(|| "main".to_string()),
+            table_prefix: query.table_prefix.clone().unwrap_or_default(),
+            path_separator: query.path_separator.clone().unwrap_or_else(|| "_".to_string()),
+        }
+    }
+
+    /// Where a table's field is found in the JSON Schema: at the top for the main table and
+    /// under the table's path otherwise.
+    fn schema_path(&self, table: &Table, field_name: &str) -> String {
+        let table_name = table.name.strip_prefix(self.table_prefix.as_str()).unwrap_or(&table.name);
+        if table_name == self.main_table_name {
+            field_name.to_string()
+        } else {
+            format!("{}{}{}", table_name, self.path_separator, field_name)
+        }
+    }
+}
+
+/// A table that did not fit in a sheet, so only its first rows or columns were written.
+#[derive(Serialize, Debug, Clone)]
+pub struct Truncation {
+    pub table: String,
+    pub sheet: String,
+    pub rows: u64,
+    pub rows_written: u64,
+    pub columns: usize,
+    pub columns_written: usize,
+}
+
+impl Truncation {
+    fn message(&self) -> String {
+        let mut parts = vec![];
+        if self.rows_written < self.rows {
+            parts.push(format!("only the first {} of {} rows", self.rows_written, self.rows));
+        }
+        if self.columns_written < self.columns {
+            parts.push(format!("only the first {} of {} columns", self.columns_written, self.columns));
+        }
+        format!("{} fit in Excel's limits, use the CSV output for the rest", parts.join(" and "))
+    }
+}
+
+/// A table with more rows or columns than fit in a sheet.
+#[derive(Serialize, Debug, Clone)]
+pub struct Overflow {
+    pub table: String,
+    pub title: String,
+    pub rows: u64,
+    pub columns: usize,
+}
+
+/// The tables of an output that do not fit in a sheet, going by the counts in `fields.csv`
+/// so the CSV files are not read. Every row has a `_link`, so the largest count of a table's
+/// fields is its number of rows. `max_rows` is the rows in a sheet, including the header.
+pub fn find_overflows(output_path: &Path, max_rows: u64) -> csv::Result<Vec<Overflow>> {
+    let mut rows: HashMap<String, u64> = HashMap::new();
+    for record in csv::Reader::from_path(output_path.join("fields.csv"))?.deserialize() {
+        let record: HashMap<String, String> = record?;
+        let count = record.get("count").and_then(|count| count.parse().ok()).unwrap_or(0);
+        let table_rows = rows.entry(record.get("table_name").cloned().unwrap_or_default()).or_default();
+        *table_rows = (*table_rows).max(count);
+    }
+
+    Ok(relationships::output_tables(output_path)?
+        .into_iter()
+        .map(|table| Overflow {
+            rows: rows.get(&table.name).copied().unwrap_or(0),
+            columns: table.fields.len(),
+            table: table.name,
+            title: table.title,
+        })
+        .filter(|overflow| overflow.rows >= max_rows || overflow.columns > EXCEL_MAX_COLUMNS)
+        .collect())
+}
+
+/// The warning added to a preview when some tables do not fit in a sheet.
+pub fn overflow_warning(overflows: &[Overflow], max_rows: u64) -> Value {
+    json!({
+        "type": "excel_limits",
+        "message": format!(
+            "{} table(s) have more than the {} rows or {} columns an Excel sheet holds, so the xlsx output cuts them short. Set `xlsx_split=true` to continue their rows on more sheets, or use another output format.",
+            overflows.len(),
+            max_rows - 1,
+            EXCEL_MAX_COLUMNS
+        ),
+        "max_rows": max_rows - 1,
+        "max_columns": EXCEL_MAX_COLUMNS,
+        "tables": overflows,
+    })
+}
+
+fn xlsx_error(error: XlsxError) -> std::io::Error {
+    std::io::Error::other(error.to_string())
+}
+
+/// Follows a `$ref` within the schema document, other references are left unresolved.
+fn resolve<'a>(schema: &'a Value, property: &'a Value) -> &'a Value {
+    let mut property = property;
+    for _ in 0..32 {
+        match property.get("$ref").and_then(Value::as_str).and_then(|reference| reference.strip_prefix('#')) {
+            Some(pointer) => match schema.pointer(pointer) {
+                Some(target) => property = target,
+                None => break,
+            },
+            None => break,
+        }
+    }
+    property
+}
+
+fn collect_descriptions(
+    schema: &Value,
+    properties: &Value,
+    path: &[String],
+    path_separator: &str,
+    descriptions: &mut HashMap<String, String>,
+    depth: usize,
+) {
+    let Some(properties) = resolve(schema, properties).as_object() else {
+        return;
+    };
+    // Recursive schemas would go on forever.
+    if depth > 32 {
+        return;
+    }
+    for (name, property) in properties {
+        let property = resolve(schema, property);
+        let mut new_path = path.to_vec();
+        new_path.push(name.clone());
+        if let Some(properties) = property.get("properties") {
+            collect_descriptions(schema, properties, &new_path, path_separator, descriptions, depth + 1);
+        } else if let Some(properties) = property.get("items").map(|items| resolve(schema, items)).and_then(|items| items.get("properties")) {
+            collect_descriptions(schema, properties, &new_path, path_separator, descriptions, depth + 1);
+        } else if let Some(description) = property.get("description").and_then(Value::as_str) {
+            descriptions.insert(new_path.join(path_separator), description.to_string());
+        }
+    }
+}
+
+/// Descriptions of the fields in a JSON Schema, keyed by their path joined with
+/// `path_separator` the way libflatterer names fields.
+fn field_descriptions(schema: &Value, path_separator: &str) -> HashMap<String, String> {
+    let mut descriptions = HashMap::new();
+    if let Some(properties) = schema.get("properties") {
+        collect_descriptions(schema, properties, &[], path_separator, &mut descriptions, 0);
+    }
+    descriptions
+}
+
+/// A sheet name Excel accepts: without `[]:*?/\`, at most 31 characters and unique, ignoring
+/// case, among `used`.
+fn sheet_name(title: &str, path_separator: &str, used: &mut HashSet<String>) -> String {
+    let cleaned: String = title.chars().filter(|c| !"[]:*?/\\".contains(*c)).collect();
+    let cleaned = cleaned.trim_matches('\'').to_string();
+    let base = if cleaned.is_empty() {
+        "Sheet".to_string()
+    } else if cleaned.is_ascii() {
+        libflatterer::truncate_xlsx_title(cleaned, path_separator)
+    } else {
+        cleaned.chars().take(EXCEL_MAX_SHEET_NAME).collect()
+    };
+
+    let mut name = base.clone();
+    let mut suffix = 2;
+    while !used.insert(name.to_lowercase()) {
+        let suffix_text = format!("~{}", suffix);
+        let kept: String = base.chars().take(EXCEL_MAX_SHEET_NAME - suffix_text.len()).collect();
+        name = format!("{}{}", kept, suffix_text);
+        suffix += 1;
+    }
+    name
+}
+
+/// The name of the `number`th sheet of a table split across sheets, like `items (2)`.
+fn continuation_name(sheet: &str, number: usize, used: &mut HashSet<String>) -> String {
+    let suffix = format!(" ({})", number);
+    let kept: String = sheet.chars().take(EXCEL_MAX_SHEET_NAME - suffix.len()).collect();
+    sheet_name(&format!("{}{}", kept, suffix), "", used)
+}
+
+/// Cut a cell down to what Excel can hold, at a character boundary.
+fn fit_cell(value: &str) -> &str {
+    if value.len() <= EXCEL_MAX_CELL_LENGTH {
+        return value;
+    }
+    let mut end = EXCEL_MAX_CELL_LENGTH;
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+    &value[..end]
+}
+
+/// The cell formats of a workbook.
+struct Formats {
+    header: Format,
+    date: Format,
+    datetime: Format,
+}
+
+impl Formats {
+    fn new() -> Formats {
+        Formats {
+            header: Format::new().set_bold(),
+            date: Format::new().set_num_format("yyyy-mm-dd"),
+            datetime: Format::new().set_num_format("yyyy-mm-dd hh:mm:ss"),
+        }
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(value: &str, digits: usize) -> Option<T> {
+    (value.len() == digits && value.bytes().all(|byte| byte.is_ascii_digit())).then(|| value.parse().ok()).flatten()
+}
+
+/// A date as `YYYY-MM-DD`, optionally followed by a time as `HH:MM:SS`, with fractional
+/// seconds and a `Z` allowed, and whether it has the time. Values with a UTC offset are
+/// left as text rather than shifted.
+fn parse_datetime(value: &str) -> Option<(ExcelDateTime, bool)> {
+    let (date, time) = match value.get(10..) {
+        Some("") => (value, None),
+        Some(rest) if rest.starts_with(['T', ' ']) => (&value[..10], Some(rest[1..].strip_suffix('Z').unwrap_or(&rest[1..]))),
+        _ => return None,
+    };
+    let mut date_parts = date.split('-');
+    let year = parse_number(date_parts.next()?, 4)?;
+    let month = parse_number(date_parts.next()?, 2)?;
+    let day = parse_number(date_parts.next()?, 2)?;
+    let datetime = ExcelDateTime::from_ymd(year, month, day).ok()?;
+    let Some(time) = time else {
+        return Some((datetime, false));
+    };
+    let (time, fraction) = time.split_once('.').unwrap_or((time, ""));
+    if !fraction.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    let mut time_parts = time.split(':');
+    let hour = parse_number(time_parts.next()?, 2)?;
+    let minute = parse_number(time_parts.next()?, 2)?;
+    let second: f64 = parse_number(time_parts.next()?, 2)?;
+    if time_parts.next().is_some() || hour > 23 {
+        return None;
+    }
+    let fraction: f64 = if fraction.is_empty() { 0.0 } else { format!("0.{}", fraction).parse().ok()? };
+    Some((datetime.and_hms(hour, minute, second + fraction).ok()?, true))
+}
+
+fn write_cell(worksheet: &mut Worksheet, row: u32, column: u16, value: &str, field_type: &str, formats: &Formats) -> Result<(), XlsxError> {
+    if value.is_empty() {
+        return Ok(());
+    }
+    match field_type {
+        "number" | "integer" => {
+            // Written as text so they keep every digit.
+            let inexact = value.parse::<i128>().is_ok_and(|integer| integer.abs() > MAX_EXACT_INTEGER);
+            if let Some(number) = value.parse::<f64>().ok().filter(|number| number.is_finite() && !inexact) {
+                worksheet.write_number(row, column, number)?;
+                return Ok(());
+            }
+        }
+        "boolean" => match value {
+            "true" | "TRUE" | "True" => {
+                worksheet.write_boolean(row, column, true)?;
+                return Ok(());
+            }
+            "false" | "FALSE" | "False" => {
+                worksheet.write_boolean(row, column, false)?;
+                return Ok(());
+            }
+            _ => {}
+        },
+        "date" | "datetime" => {
+            if let Some((datetime, has_time)) = parse_datetime(value) {
+                let format = if has_time { &formats.datetime } else { &formats.date };
+                worksheet.write_datetime_with_format(row, column, datetime, format)?;
+                return Ok(());
+            }
+        }
+        _ => {}
+    }
+    worksheet.write_string(row, column, fit_cell(value))?;
+    Ok(())
+}
+
+/// What was written of a table: `rows_written` of the `rows` in its CSV file, on `sheets`.
+struct Written {
+    rows: u64,
+    rows_written: u64,
+    sheets: Vec<String>,
+}
+
+/// Adds a sheet for a table with its header row.
+fn add_sheet<'a>(workbook: &'a mut Workbook, name: &str, table: &Table, columns: usize, formats: &Formats) -> Result<&'a mut Worksheet, XlsxError> {
+    let worksheet = workbook.add_worksheet_with_low_memory();
+    worksheet.set_name(name)?;
+    for (column, field) in table.fields[..columns].iter().enumerate() {
+        worksheet.write_string_with_format(0, column as u16, fit_cell(&field.title), &formats.header)?;
+    }
+    Ok(worksheet)
+}
+
+fn finish_sheet(worksheet: &mut Worksheet, widths: &[usize], options: &XlsxOptions) -> Result<(), XlsxError> {
+    if options.freeze_header {
+        worksheet.set_freeze_panes(1, 0)?;
+    }
+    if options.autofit {
+        set_widths(worksheet, widths)?;
+    }
+    Ok(())
+}
+
+/// Writes a table's CSV file into `sheet`, stopping at Excel's column limit and at its row
+/// limit unless `split`, when the rows carry on in further sheets.
+fn write_table(
+    workbook: &mut Workbook,
+    output_path: &Path,
+    table: &Table,
+    sheet: &str,
+    options: &XlsxOptions,
+    formats: &Formats,
+    used: &mut HashSet<String>,
+) -> std::io::Result<Written> {
+    let columns = table.fields.len().min(EXCEL_MAX_COLUMNS);
+    let header_widths: Vec<usize> = table.fields[..columns].iter().map(|field| field.title.chars().count()).collect();
+    let mut widths = header_widths.clone();
+    let mut sheets = vec![sheet.to_string()];
+    let mut worksheet = add_sheet(workbook, sheet, table, columns, formats).map_err(xlsx_error)?;
+
+    let mut reader = csv::Reader::from_path(output_path.join("csv").join(format!("{}.csv", table.title)))?;
+    let (mut rows, mut rows_written, mut sheet_row) = (0, 0, 0_u32);
+    for record in reader.records() {
+        let record = record?;
+        rows += 1;
+        if u64::from(sheet_row) + 1 >= options.max_rows {
+            if !options.split {
+                continue;
+            }
+            finish_sheet(worksheet, &widths, options).map_err(xlsx_error)?;
+            let name = continuation_name(sheet, sheets.len() + 1, used);
+            worksheet = add_sheet(workbook, &name, table, columns, formats).map_err(xlsx_error)?;
+            sheets.push(name);
+            widths = header_widths.clone();
+            sheet_row = 0;
+        }
+        sheet_row += 1;
+        rows_written += 1;
+        for (column, value) in record.iter().take(columns).enumerate() {
+            write_cell(worksheet, sheet_row, column as u16, value, &table.fields[column].field_type, formats).map_err(xlsx_error)?;
+            if options.autofit {
+                widths[column] = widths[column].max(value.chars().take(MAX_COLUMN_WIDTH).count());
+            }
+        }
+    }
+    finish_sheet(worksheet, &widths, options).map_err(xlsx_error)?;
+    Ok(Written { rows, rows_written, sheets })
+}
+
+fn set_widths(worksheet: &mut Worksheet, widths: &[usize]) -> Result<(), XlsxError> {
+    for (column, width) in widths.iter().enumerate() {
+        worksheet.set_column_width(column as u16, (*width).clamp(4, MAX_COLUMN_WIDTH) as f64 + 2.0)?;
+    }
+    Ok(())
+}
+
+fn describe_field(field_name: &str, table: &Table, tables: &[Table]) -> Option<String> {
+    if field_name == "_link" {
+        let child_key = format!("_link_{}", table.name);
+        let has_children = tables.iter().any(|child| child.fields.iter().any(|field| field.name == child_key));
+        return Some(if has_children {
+            format!("Identifies the row within `{}`, referenced by the `{}` column of its child tables.", table.title, child_key)
+        } else {
+            format!("Identifies the row within `{}`.", table.title)
+        });
+    }
+    let parent_name = field_name.strip_prefix("_link_")?;
+    let parent_title = tables.iter().find(|parent| parent.name == parent_name).map_or(parent_name, |parent| parent.title.as_str());
+    Some(format!("The `_link` of the row in `{}` this row belongs to.", parent_title))
+}
+
+/// Lists each sheet's table and fields, with the field descriptions from the JSON Schema.
+fn write_readme(
+    worksheet: &mut Worksheet,
+    tables: &[(&Table, Vec<String>)],
+    all_tables: &[Table],
+    descriptions: &HashMap<String, String>,
+    options: &XlsxOptions,
+    header: &Format,
+) -> Result<(), XlsxError> {
+    worksheet.set_name(README_SHEET)?;
+    let headings = ["sheet", "table", "column", "field", "type", "description"];
+    for (column, heading) in headings.iter().enumerate() {
+        worksheet.write_string_with_format(0, column as u16, *heading, header)?;
+    }
+    let mut widths: Vec<usize> = headings.iter().map(|heading| heading.len()).collect();
+    let mut row = 1;
+    for (table, sheets) in tables {
+        let sheet = sheets.join(", ");
+        for field in &table.fields {
+            let description = describe_field(&field.name, table, all_tables)
+                .or_else(|| descriptions.get(&options.schema_path(table, &field.name)).cloned())
+                .unwrap_or_default();
+            let values = [sheet.as_str(), &table.name, &field.title, &field.name, &field.field_type, &description];
+            for (column, value) in values.iter().enumerate() {
+                worksheet.write_string(row, column as u16, fit_cell(value))?;
+                widths[column] = widths[column].max(value.chars().take(MAX_COLUMN_WIDTH).count());
+            }
+            row += 1;
+        }
+    }
+    worksheet.set_freeze_panes(1, 0)?;
+    set_widths(worksheet, &widths)
+}
+
+fn write_warnings(worksheet: &mut Worksheet, truncations: &[Truncation], header: &Format) -> Result<(), XlsxError> {
+    worksheet.set_name(WARNINGS_SHEET)?;
+    let headings = ["sheet", "table", "warning"];
+    for (column, heading) in headings.iter().enumerate() {
+        worksheet.write_string_with_format(0, column as u16, *heading, header)?;
+    }
+    let mut widths: Vec<usize> = headings.iter().map(|heading| heading.len()).collect();
+    for (index, truncation) in truncations.iter().enumerate() {
+        let message = truncation.message();
+        let values = [truncation.sheet.as_str(), &truncation.table, &message];
+        for (column, value) in values.iter().enumerate() {
+            worksheet.write_string(index as u32 + 1, column as u16, *value)?;
+            widths[column] = widths[column].max(value.chars().take(MAX_COLUMN_WIDTH).count());
+        }
+    }
+    set_widths(worksheet, &widths)
+}
+
+/// Write `output.xlsx` from the CSV files of an output, a sheet per table with cells typed
+/// from `fields.csv`. Tables too big for a sheet are cut at Excel's limits, or with `split`
+/// have their rows continued on more sheets, and any cut short are listed in a `Warnings`
+/// sheet and returned. With `readme`, a first sheet describes each
+/// field, using the JSON Schema descriptions in `schema` if given.
+pub fn write_workbook(output_path: &Path, options: &XlsxOptions, schema: Option<&Value>) -> std::io::Result<Vec<Truncation>> {
+    let all_tables = relationships::output_tables(output_path)?;
+
+    let mut used = HashSet::new();
+    if options.readme {
+        used.insert(README_SHEET.to_lowercase());
+    }
+    used.insert(WARNINGS_SHEET.to_lowercase());
+    let tables: Vec<(&Table, String)> = all_tables
+        .iter()
+        .map(|table| (table, sheet_name(&table.title, &options.path_separator, &mut used)))
+        .collect();
+
+    let mut workbook = Workbook::new();
+    workbook.set_tempdir(output_path).map_err(xlsx_error)?;
+    let formats = Formats::new();
+    // Filled in last, when the sheets of split tables are known.
+    if options.readme {
+        workbook.add_worksheet();
+    }
+
+    let mut truncations = vec![];
+    let mut written_tables = vec![];
+    for (table, sheet) in &tables {
+        let written = write_table(&mut workbook, output_path, table, sheet, options, &formats, &mut used)?;
+        let columns_written = table.fields.len().min(EXCEL_MAX_COLUMNS);
+        if written.rows_written < written.rows || columns_written < table.fields.len() {
+            log::warn!(table = table.name.as_str(), rows = written.rows, columns = table.fields.len() as u64; "table truncated in xlsx");
+            truncations.push(Truncation {
+                table: table.name.clone(),
+                sheet: sheet.clone(),
+                rows: written.rows,
+                rows_written: written.rows_written,
+                columns: table.fields.len(),
+                columns_written,
+            });
+        }
+        written_tables.push((*table, written.sheets));
+    }
+
+    if !truncations.is_empty() {
+        write_warnings(workbook.add_worksheet(), &truncations, &formats.header).map_err(xlsx_error)?;
+    }
+    if options.readme {
+        let descriptions = schema
+            .map(|schema| field_descriptions(schema, &options.path_separator))
+            .unwrap_or_default();
+        let readme = workbook.worksheet_from_index(0).map_err(xlsx_error)?;
+        write_readme(readme, &written_tables, &all_tables, &descriptions, options, &formats.header).map_err(xlsx_error)?;
+    }
+
+    workbook.save(output_path.join(XLSX_FILE)).map_err(xlsx_error)?;
+    Ok(truncations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sheet_names() {
+        let mut used = HashSet::new();
+        let names: Vec<(&str, String)> = [
+            "main",
+            "MAIN",
+            "main",
+            "a/b\\c[d]:e*f?g",
+            "'quoted'",
+            "[]",
+            "a_very_long_table_name_that_goes_past_the_limit",
+            "a_very_long_table_name_that_goes_past_the_limit",
+            "ünïcödé_täblé_nämé_that_is_much_too_long",
+        ]
+        .into_iter()
+        .map(|title| (title, sheet_name(title, "_", &mut used)))
+        .collect();
+        insta::assert_yaml_snapshot!(names);
+    }
+
+    #[test]
+    fn continuation_names() {
+        let mut used = HashSet::new();
+        let first = sheet_name("items", "_", &mut used);
+        let long = sheet_name("a_table_name_of_thirty_one_char", "_", &mut used);
+        // A table that happens to be titled like a continuation sheet.
+        sheet_name("items (3)", "_", &mut used);
+        let names = vec![
+            continuation_name(&first, 2, &mut used),
+            continuation_name(&first, 3, &mut used),
+            continuation_name(&long, 2, &mut used),
+            continuation_name(&long, 10, &mut used),
+        ];
+        insta::assert_yaml_snapshot!(names);
+    }
+
+    /// An output with `tables`, each given as name, number of fields and rows, as far as
+    /// `fields.csv`, `tables.csv` and its CSV files existing go.
+    fn output(tables: &[(&str, usize, u64)]) -> tempfile::TempDir {
+        let output_dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(output_dir.path().join("csv")).unwrap();
+        let mut tables_writer = csv::Writer::from_path(output_dir.path().join("tables.csv")).unwrap();
+        let mut fields_writer = csv::Writer::from_path(output_dir.path().join("fields.csv")).unwrap();
+        tables_writer.write_record(["table_name", "table_title"]).unwrap();
+        fields_writer.write_record(["table_name", "field_name", "field_type", "field_title", "count"]).unwrap();
+        for (name, fields, rows) in tables {
+            tables_writer.write_record([*name, *name]).unwrap();
+            for field in 0..*fields {
+                // Only `_link` is in every row.
+                let count = if field == 0 { *rows } else { rows / 2 };
+                let field_name = if field == 0 { "_link".to_string() } else { format!("field_{}", field) };
+                fields_writer.write_record([name, field_name.as_str(), "text", field_name.as_str(), count.to_string().as_str()]).unwrap();
+            }
+            std::fs::write(output_dir.path().join("csv").join(format!("{}.csv", name)), "").unwrap();
+        }
+        tables_writer.flush().unwrap();
+        fields_writer.flush().unwrap();
+        output_dir
+    }
+
+    #[test]
+    fn overflows() {
+        let output_dir = output(&[
+            ("fits", 3, EXCEL_MAX_ROWS - 1),
+            ("too_long", 3, EXCEL_MAX_ROWS),
+            ("widest", EXCEL_MAX_COLUMNS, 10),
+            ("too_wide", EXCEL_MAX_COLUMNS + 1, 10),
+        ]);
+        let overflows = find_overflows(output_dir.path(), EXCEL_MAX_ROWS).unwrap();
+        insta::assert_yaml_snapshot!(overflow_warning(&overflows, EXCEL_MAX_ROWS));
+    }
+
+    #[test]
+    fn no_overflows() {
+        let output_dir = output(&[("main", 2, 2)]);
+        assert!(find_overflows(output_dir.path(), EXCEL_MAX_ROWS).unwrap().is_empty());
+    }
+
+    /// The parts of a workbook, by their path within the zip file.
+    fn workbook_parts(path: &Path) -> HashMap<String, String> {
+        let mut archive = zip::ZipArchive::new(std::fs::File::open(path).unwrap()).unwrap();
+        (0..archive.len())
+            .map(|index| {
+                let mut file = archive.by_index(index).unwrap();
+                let mut contents = String::new();
+                std::io::Read::read_to_string(&mut file, &mut contents).unwrap();
+                (file.name().to_string(), contents)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn typed_workbook() {
+        let job_dir = crate::tests::flatten_fixture("typed.json");
+        let output_path = job_dir.path().join("output");
+        let options = XlsxOptions { readme: true, ..XlsxOptions::from_query(&Query::default()) };
+        write_workbook(&output_path, &options, None).unwrap();
+        let parts = workbook_parts(&output_path.join(XLSX_FILE));
+
+        assert!(parts["xl/workbook.xml"].contains(r#"<sheets><sheet name="README" sheetId="1" r:id="rId1"/><sheet name="main""#));
+        let main = &parts["xl/worksheets/sheet2.xml"];
+        assert!(main.contains(r#"<pane ySplit="1" topLeftCell="A2" activePane="bottomLeft" state="frozen"/>"#));
+        // Sized to `2020-01-31T10:30:00Z`, the longest `updated`.
+        assert!(main.contains(r#"<col min="8" max="8" width="24.7109375" customWidth="1"/>"#));
+        // `big_id` is past 2^53 so stays text, with every digit.
+        assert!(main.contains(r#"<c r="F2" t="s">"#) && main.contains(r#"<c r="F3" t="s">"#));
+        assert!(parts["xl/sharedStrings.xml"].contains("<t>9007199254740993</t>"));
+        assert!(parts["xl/sharedStrings.xml"].contains("<t>-9007199254740995</t>"));
+        assert!(main.contains(r#"<c r="E2"><v>10.5</v></c>"#));
+        assert!(main.contains(r#"<c r="G2" s="2"><v>43861</v></c>"#));
+        assert!(main.contains(r#"<c r="H2" s="3"><v>43861.4375</v></c>"#));
+        let styles = &parts["xl/styles.xml"];
+        assert!(styles.contains(r#"<numFmt numFmtId="164" formatCode="yyyy-mm-dd"/>"#));
+        assert!(styles.contains(r#"<numFmt numFmtId="165" formatCode="yyyy-mm-dd hh:mm:ss"/>"#));
+    }
+
+    #[test]
+    fn datetimes() {
+        let parsed: Vec<(&str, Option<(f64, bool)>)> = [
+            "2020-01-31",
+            "2020-01-31T10:30:00Z",
+            "2020-01-31 10:30:00.5",
+            "2020-02-30",
+            "2020-01-31T10:30:00+01:00",
+            "2020-01-31T25:00:00",
+            "2020-1-31",
+            "31/01/2020",
+        ]
+        .into_iter()
+        .map(|value| (value, parse_datetime(value).map(|(datetime, has_time)| (datetime.to_excel(), has_time))))
+        .collect();
+        insta::assert_yaml_snapshot!(parsed);
+    }
+
+    /// The names of a workbook's sheets, in order.
+    fn sheet_list(parts: &HashMap<String, String>) -> Vec<String> {
+        parts["xl/workbook.xml"]
+            .split("<sheet name=\"")
+            .skip(1)
+            .map(|sheet| sheet[..sheet.find('"').unwrap()].to_string())
+            .collect()
+    }
+
+    /// The cells of `xl/worksheets/sheet{number}.xml` by row, with shared strings looked up.
+    fn sheet_cells(parts: &HashMap<String, String>, number: usize) -> Vec<Vec<String>> {
+        let strings: Vec<&str> = parts["xl/sharedStrings.xml"]
+            .split("<t>")
+            .skip(1)
+            .map(|string| &string[..string.find("</t>").unwrap()])
+            .collect();
+        parts[&format!("xl/worksheets/sheet{}.xml", number)]
+            .split("<row ")
+            .skip(1)
+            .map(|row| {
+                row.split("<c ")
+                    .skip(1)
+                    .map(|cell| {
+                        let value = cell.split("<v>").nth(1).map_or("", |value| &value[..value.find("</v>").unwrap()]);
+                        if cell.contains(r#"t="s""#) {
+                            strings[value.parse::<usize>().unwrap()].to_string()
+                        } else {
+                            value.to_string()
+                        }
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn split_sheets() {
+        let job_dir = crate::tests::flatten_fixture("typed.json");
+        let output_path = job_dir.path().join("output");
+        // The header and one row to a sheet.
+        let options = XlsxOptions { split: true, max_rows: 2, ..XlsxOptions::from_query(&Query::default()) };
+        assert!(write_workbook(&output_path, &options, None).unwrap().is_empty());
+        let parts = workbook_parts(&output_path.join(XLSX_FILE));
+        assert_eq!(sheet_list(&parts), ["main", "main (2)", "tags", "tags (2)", "tags (3)"]);
+        let links: Vec<Vec<String>> = (1..=5).map(|number| sheet_cells(&parts, number).into_iter().map(|row| row[0].clone()).collect()).collect();
+        assert_eq!(links, [["_link", "0"], ["_link", "1"], ["_link", "0.tags.0"], ["_link", "0.tags.1"], ["_link", "1.tags.0"]]);
+    }
+
+    #[test]
+    fn warnings_sheet() {
+        let job_dir = crate::tests::flatten_fixture("typed.json");
+        let output_path = job_dir.path().join("output");
+        let options = XlsxOptions { max_rows: 2, ..XlsxOptions::from_query(&Query::default()) };
+        let truncations = write_workbook(&output_path, &options, None).unwrap();
+        let parts = workbook_parts(&output_path.join(XLSX_FILE));
+        assert_eq!(sheet_list(&parts), ["main", "tags", "Warnings"]);
+        assert_eq!(sheet_cells(&parts, 1).len(), 2);
+        assert_eq!(sheet_cells(&parts, 2).len(), 2);
+        insta::assert_yaml_snapshot!(sheet_cells(&parts, 3));
+
+        // The preview warns of the same tables, with the keys `Home.vue` shows.
+        let warning = overflow_warning(&find_overflows(&output_path, 2).unwrap(), 2);
+        assert_eq!(warning["type"], "excel_limits");
+        assert!(warning["message"].as_str().unwrap().starts_with("2 table(s) have more than the 1 rows"));
+        let titles: Vec<&str> = warning["tables"].as_array().unwrap().iter().map(|table| table["title"].as_str().unwrap()).collect();
+        let truncated: Vec<&str> = truncations.iter().map(|truncation| truncation.sheet.as_str()).collect();
+        assert_eq!(titles, truncated);
+    }
+}